@@ -7,13 +7,19 @@ pub struct Adsr{
     dec_value: f32,
     sus_value: f32,
     rel_value: f32,
+    delay_value: f32,
+    hold_value: f32,
+    // Samples spent in the current delay or hold stage
+    stage_samples: f32,
     sample_rate: f32,
     pub envelope_value: f32,
     pub state: AdsrState,
 }
 #[derive(PartialEq, Debug, Clone)]
 pub enum AdsrState{
+    Delay,
     Attack,
+    Hold,
     Decay,
     Sustain,
     Release,
@@ -32,6 +38,9 @@ impl Adsr{
             dec_value: 0.1,
             sus_value: 1.0,
             rel_value: 0.1,
+            delay_value: 0.0,
+            hold_value: 0.0,
+            stage_samples: 0.0,
             sample_rate: sample_rate_,
             envelope_value: 0.0,
             state: AdsrState::Inactive,
@@ -44,6 +53,20 @@ impl Adsr{
     pub fn get_next_sample(&mut self)->f32{
         match self.state{
             AdsrState::Inactive => 0.0,
+            AdsrState::Delay => {
+                self.stage_samples += 1.0;
+                if self.stage_samples >= self.delay_value * self.sample_rate{
+                    self.get_next_state();
+                }
+                0.0
+            },
+            AdsrState::Hold => {
+                self.stage_samples += 1.0;
+                if self.stage_samples >= self.hold_value * self.sample_rate{
+                    self.get_next_state();
+                }
+                self.envelope_value
+            },
             AdsrState::Attack => {
                 self.envelope_value += self.atk_step;
                 if self.envelope_value >= 1.0{
@@ -106,6 +129,14 @@ impl Adsr{
         }
         self.rel_step = self.get_step(self.sus_value, self.rel_value);
     }
+    /// Sets the time before the attack starts in seconds
+    pub fn set_delay(&mut self, delay_:f32){
+        self.delay_value = delay_.max(0.0);
+    }
+    /// Sets how long the envelope holds at full level after the attack in seconds
+    pub fn set_hold(&mut self, hold_:f32){
+        self.hold_value = hold_.max(0.0);
+    }
     /// Sets attack, decay, sustain, and release (A,D, and R are in seconds; S: 0-1)
     pub fn set_adsr(&mut self, attack_:f32, decay_:f32, sustain_:f32, release_:f32){
         self.set_sustain(sustain_);
@@ -118,7 +149,12 @@ impl Adsr{
     }
     /// Triggers the attack stage of the ADSR
     pub fn note_on(&mut self){
-        self.state = AdsrState::Attack;
+        self.stage_samples = 0.0;
+        if self.delay_value > 0.0{
+            self.state = AdsrState::Delay;
+        }else{
+            self.state = AdsrState::Attack;
+        }
     } 
     /// Triggers the release stage of the ADSR
     pub fn note_off(&mut self){
@@ -142,7 +178,12 @@ impl Adsr{
     fn get_next_state(&mut self){
         match self.state{
             AdsrState::Inactive => self.state = AdsrState::Inactive,
-            AdsrState::Attack => {
+            AdsrState::Delay => self.state = AdsrState::Attack,
+            AdsrState::Attack if self.hold_value > 0.0 => {
+                self.stage_samples = 0.0;
+                self.state = AdsrState::Hold;
+            },
+            AdsrState::Attack | AdsrState::Hold => {
                 if self.dec_value > 0.0{
                     self.state = AdsrState::Decay;
                 }else{
@@ -217,5 +258,23 @@ mod tests{
             }
         }
     }
+    #[test]
+    fn test_delay_hold(){
+        let mut adsr = Adsr::new(50.0, 0.2, 0.1,0.5,0.2);
+        adsr.set_delay(0.1);
+        adsr.set_hold(0.1);
+        adsr.note_on();
+        for i in 0..21{
+            let value = adsr.get_next_sample();
+            if i < 5{
+                assert_eq!(value, 0.0);
+            }else if i < 15{
+                assert_close!(value, (i-4) as f32*0.1, 0.001);
+            }else if i < 20{
+                assert_eq!(value, 1.0);
+            }else{
+                assert_close!(value, 0.9, 0.001);
+            }
+        }
+    }
 }
-
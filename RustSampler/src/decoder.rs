@@ -0,0 +1,623 @@
+use crate::sampler_voice::SustainModes;
+use hound::SampleFormat;
+use std::{fmt, fs, io::{self, Read}, path::{Path, PathBuf}};
+
+/// The number of bytes read from the start of a file to work out its format
+const SNIFF_LENGTH: usize = 16;
+
+/// Audio decoded from a file, with its channels interleaved
+pub struct DecodedAudio{
+    pub samples: Vec<f32>,
+    pub num_channels: usize,
+    pub sample_rate: f32,
+    pub metadata: SampleMetadata,
+}
+
+/// How a sample should be played, as stored in a wav's `smpl`, `inst` and `cue ` chunks
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct SampleMetadata{
+    /// The note the sample sounds at when played back unchanged
+    pub root_note: Option<u8>,
+    /// Fine tuning in cents to apply on playback
+    pub fine_tune: f32,
+    /// Gain in dB to apply on playback
+    pub gain: f32,
+    pub key_range: Option<(u8, u8)>,
+    pub velocity_range: Option<(u8, u8)>,
+    /// The first loop of the `smpl` chunk
+    pub sustain_loop: Option<SampleLoop>,
+    /// Positions of the `cue ` markers, in frames
+    pub cues: Vec<u32>,
+}
+
+/// A loop in frames. `end` is the last frame of the loop, not the one after it
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SampleLoop{
+    pub start: u32,
+    pub end: u32,
+    pub mode: SustainModes,
+}
+
+/// Why an audio file couldn't be loaded
+#[derive(Debug)]
+pub enum LoadError{
+    Io{ path: PathBuf, error: io::Error },
+    /// The file isn't in any format there is a decoder for
+    UnsupportedFormat{ path: PathBuf },
+    UnsupportedBitDepth{ path: PathBuf, bits: u16 },
+    /// The file decoded to no audio at all
+    Empty{ path: PathBuf },
+    /// The file is in a supported format but is damaged
+    Malformed{ path: PathBuf, format: &'static str, message: String },
+}
+
+impl fmt::Display for LoadError{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>)->fmt::Result{
+        match self{
+            LoadError::Io{ path, error } => write!(f, "Couldn't read {}: {}", path.display(), error),
+            LoadError::UnsupportedFormat{ path } => write!(f, "{} isn't in a supported audio format", path.display()),
+            LoadError::UnsupportedBitDepth{ path, bits } => write!(f, "{} has an unsupported bit depth of {}", path.display(), bits),
+            LoadError::Empty{ path } => write!(f, "{} has no audio in it", path.display()),
+            LoadError::Malformed{ path, format, message } => write!(f, "Couldn't decode {} as {}: {}", path.display(), format, message),
+        }
+    }
+}
+
+impl std::error::Error for LoadError{}
+
+/// Why a decoder failed, before it's known which file it was decoding
+#[derive(Debug)]
+pub enum DecodeError{
+    UnsupportedBitDepth(u16),
+    Malformed(String),
+}
+
+/// Decodes one audio file format. Add an implementation to `DECODERS` to support
+/// another format everywhere samples are loaded
+pub trait AudioDecoder: Sync{
+    /// The format's name, for error messages
+    fn name(&self)->&'static str;
+    /// Lowercase file extensions of the format, used when sniffing fails
+    fn extensions(&self)->&'static [&'static str];
+    /// Returns whether the first bytes of a file look like this format
+    fn sniff(&self, header: &[u8])->bool;
+    fn decode(&self, data: Vec<u8>)->Result<DecodedAudio, DecodeError>;
+}
+
+/// Every supported format, in the order their headers are checked
+const DECODERS: [&dyn AudioDecoder; 5] = [&WavDecoder, &AiffDecoder, &FlacDecoder, &VorbisDecoder, &Mp3Decoder];
+
+pub struct WavDecoder;
+pub struct AiffDecoder;
+pub struct FlacDecoder;
+pub struct VorbisDecoder;
+pub struct Mp3Decoder;
+
+impl AudioDecoder for WavDecoder{
+    fn name(&self)->&'static str{
+        "WAV"
+    }
+    fn extensions(&self)->&'static [&'static str]{
+        &["wav", "wave"]
+    }
+    fn sniff(&self, header: &[u8])->bool{
+        header.len() >= 12 && &header[0..4] == b"RIFF" && &header[8..12] == b"WAVE"
+    }
+    fn decode(&self, data: Vec<u8>)->Result<DecodedAudio, DecodeError>{
+        let metadata = read_wav_metadata(&data);
+        let reader = hound::WavReader::new(io::Cursor::new(data)).map_err(|error| DecodeError::Malformed(error.to_string()))?;
+        let spec = reader.spec();
+        let samples: Result<Vec<f32>, hound::Error> = match spec.sample_format{
+            SampleFormat::Float => {
+                if spec.bits_per_sample != 32{
+                    return Err(DecodeError::UnsupportedBitDepth(spec.bits_per_sample))
+                }
+                reader.into_samples::<f32>().collect()
+            },
+            SampleFormat::Int => {
+                if !(8..=32).contains(&spec.bits_per_sample){
+                    return Err(DecodeError::UnsupportedBitDepth(spec.bits_per_sample))
+                }
+                // Full scale is 2^(bits - 1) whatever the depth. 8 bit files are stored
+                // unsigned, hound centres them on zero as it reads them
+                let scale = 1.0 / (1u64 << (spec.bits_per_sample - 1)) as f32;
+                reader.into_samples::<i32>().map(|sample| sample.map(|sample| sample as f32 * scale)).collect()
+            },
+        };
+        Ok(DecodedAudio{
+            samples: samples.map_err(|error| DecodeError::Malformed(error.to_string()))?,
+            num_channels: spec.channels as usize,
+            sample_rate: spec.sample_rate as f32,
+            metadata,
+        })
+    }
+}
+
+impl AudioDecoder for AiffDecoder{
+    fn name(&self)->&'static str{
+        "AIFF"
+    }
+    fn extensions(&self)->&'static [&'static str]{
+        &["aif", "aiff", "aifc"]
+    }
+    fn sniff(&self, header: &[u8])->bool{
+        header.len() >= 12 && &header[0..4] == b"FORM" && (&header[8..12] == b"AIFF" || &header[8..12] == b"AIFC")
+    }
+    fn decode(&self, data: Vec<u8>)->Result<DecodedAudio, DecodeError>{
+        decode_with_symphonia(data, "aiff")
+    }
+}
+
+impl AudioDecoder for FlacDecoder{
+    fn name(&self)->&'static str{
+        "FLAC"
+    }
+    fn extensions(&self)->&'static [&'static str]{
+        &["flac"]
+    }
+    fn sniff(&self, header: &[u8])->bool{
+        header.starts_with(b"fLaC")
+    }
+    fn decode(&self, data: Vec<u8>)->Result<DecodedAudio, DecodeError>{
+        decode_with_symphonia(data, "flac")
+    }
+}
+
+impl AudioDecoder for VorbisDecoder{
+    fn name(&self)->&'static str{
+        "Ogg Vorbis"
+    }
+    fn extensions(&self)->&'static [&'static str]{
+        &["ogg", "oga"]
+    }
+    fn sniff(&self, header: &[u8])->bool{
+        header.starts_with(b"OggS")
+    }
+    fn decode(&self, data: Vec<u8>)->Result<DecodedAudio, DecodeError>{
+        decode_with_symphonia(data, "ogg")
+    }
+}
+
+impl AudioDecoder for Mp3Decoder{
+    fn name(&self)->&'static str{
+        "MP3"
+    }
+    fn extensions(&self)->&'static [&'static str]{
+        &["mp3"]
+    }
+    fn sniff(&self, header: &[u8])->bool{
+        // Either an ID3 tag or the sync word of an MPEG layer III frame
+        header.starts_with(b"ID3") || (header.len() >= 2 && header[0] == 0xff && header[1] & 0xe6 == 0xe2)
+    }
+    fn decode(&self, data: Vec<u8>)->Result<DecodedAudio, DecodeError>{
+        decode_with_symphonia(data, "mp3")
+    }
+}
+
+/// Decodes the first audio track of a file with symphonia. `extension` hints at the
+/// container so probing starts with the right reader
+fn decode_with_symphonia(data: Vec<u8>, extension: &str)->Result<DecodedAudio, DecodeError>{
+    use symphonia::core::{audio::SampleBuffer as InterleavedBuffer, codecs::DecoderOptions, errors::Error,
+        formats::FormatOptions, io::MediaSourceStream, meta::MetadataOptions, probe::Hint};
+    let stream = MediaSourceStream::new(Box::new(io::Cursor::new(data)), Default::default());
+    let mut hint = Hint::new();
+    hint.with_extension(extension);
+    // Gapless playback trims the encoder delay and padding of MP3 and Vorbis files
+    let format_options = FormatOptions{ enable_gapless: true, ..Default::default() };
+    let probed = symphonia::default::get_probe()
+        .format(&hint, stream, &format_options, &MetadataOptions::default())
+        .map_err(|error| DecodeError::Malformed(error.to_string()))?;
+    let mut format = probed.format;
+    let track = format.default_track().ok_or(DecodeError::Malformed("there is no audio track".to_string()))?;
+    let track_id = track.id;
+    let mut sample_rate = track.codec_params.sample_rate.unwrap_or(0) as f32;
+    let mut num_channels = track.codec_params.channels.map_or(0, |channels| channels.count());
+    let mut decoder = symphonia::default::get_codecs()
+        .make(&track.codec_params, &DecoderOptions::default())
+        .map_err(|error| DecodeError::Malformed(error.to_string()))?;
+    let mut samples = Vec::new();
+    loop{
+        let packet = match format.next_packet(){
+            Ok(packet) => packet,
+            Err(Error::IoError(error)) if error.kind() == io::ErrorKind::UnexpectedEof => break,
+            Err(error) => return Err(DecodeError::Malformed(error.to_string())),
+        };
+        if packet.track_id() != track_id{
+            continue
+        }
+        match decoder.decode(&packet){
+            Ok(decoded) => {
+                let spec = *decoded.spec();
+                sample_rate = spec.rate as f32;
+                num_channels = spec.channels.count();
+                let mut interleaved = InterleavedBuffer::<f32>::new(decoded.capacity() as u64, spec);
+                interleaved.copy_interleaved_ref(decoded);
+                samples.extend_from_slice(interleaved.samples());
+            },
+            // A corrupt frame is skipped rather than losing the whole file
+            Err(Error::DecodeError(_)) => continue,
+            Err(error) => return Err(DecodeError::Malformed(error.to_string())),
+        }
+    }
+    Ok(DecodedAudio{
+        samples,
+        num_channels,
+        sample_rate,
+        metadata: SampleMetadata::default(),
+    })
+}
+
+fn read_u32(data: &[u8], at: usize)->u32{
+    u32::from_le_bytes([data[at], data[at + 1], data[at + 2], data[at + 3]])
+}
+
+/// Reads the `smpl`, `inst` and `cue ` chunks of a wav file. Chunks that are missing
+/// or too short leave their settings at the defaults
+pub fn read_wav_metadata(data: &[u8])->SampleMetadata{
+    let mut metadata = SampleMetadata::default();
+    if data.len() < 12 || &data[0..4] != b"RIFF" || &data[8..12] != b"WAVE"{
+        return metadata
+    }
+    let mut smpl_tuning = None;
+    let mut pos = 12;
+    while pos + 8 <= data.len(){
+        let id = &data[pos..pos + 4];
+        let size = read_u32(data, pos + 4) as usize;
+        let Some(chunk) = data.get(pos + 8..pos + 8 + size) else {
+            break
+        };
+        match id{
+            b"smpl" if chunk.len() >= 36 => {
+                let unity_note = read_u32(chunk, 12);
+                // The pitch fraction is how far above the unity note the sample sounds,
+                // so it's corrected by tuning down
+                let cents = read_u32(chunk, 16) as f32 / 4294967296.0 * 100.0;
+                if unity_note <= 127{
+                    metadata.root_note = Some(unity_note as u8);
+                    smpl_tuning = Some(-cents);
+                }
+                let num_loops = read_u32(chunk, 28) as usize;
+                if num_loops > 0 && chunk.len() >= 36 + 24{
+                    let sample_loop = &chunk[36..60];
+                    let mode = match read_u32(sample_loop, 4){
+                        1 => SustainModes::LoopBounce,
+                        // Backward loops are played forwards
+                        _ => SustainModes::LoopWrap,
+                    };
+                    let start = read_u32(sample_loop, 8);
+                    let end = read_u32(sample_loop, 12);
+                    if end > start{
+                        metadata.sustain_loop = Some(SampleLoop{ start, end, mode });
+                    }
+                }
+            },
+            b"inst" if chunk.len() >= 7 => {
+                // The smpl chunk's note and tuning are the more precise, so they win
+                if metadata.root_note.is_none() && chunk[0] <= 127{
+                    metadata.root_note = Some(chunk[0]);
+                }
+                if smpl_tuning.is_none(){
+                    metadata.fine_tune = chunk[1] as i8 as f32;
+                }
+                metadata.gain = chunk[2] as i8 as f32;
+                metadata.key_range = Some((chunk[3].min(127), chunk[4].min(127)));
+                metadata.velocity_range = Some((chunk[5].min(127), chunk[6].min(127)));
+            },
+            b"cue " if chunk.len() >= 4 => {
+                let num_cues = read_u32(chunk, 0) as usize;
+                metadata.cues = chunk[4..].chunks_exact(24)
+                    .take(num_cues)
+                    .map(|cue| read_u32(cue, 20))
+                    .collect();
+            },
+            _ => (),
+        }
+        // Chunks are padded to an even length
+        pos += 8 + size + size % 2;
+    }
+    if let Some(cents) = smpl_tuning{
+        metadata.fine_tune = cents;
+    }
+    metadata
+}
+
+/// Returns a copy of a wav file with the loop, root note, tuning and cues of `metadata`
+/// written into its `smpl` and `cue ` chunks, replacing any it had. Every other chunk,
+/// the audio included, is copied byte for byte.
+/// 
+/// Returns `None` if the data isn't a wav file
+pub fn write_wav_metadata(data: &[u8], metadata: &SampleMetadata)->Option<Vec<u8>>{
+    if data.len() < 12 || &data[0..4] != b"RIFF" || &data[8..12] != b"WAVE"{
+        return None
+    }
+    let mut file = data[0..12].to_vec();
+    let mut sample_rate = None;
+    let mut pos = 12;
+    while pos + 8 <= data.len(){
+        let id = &data[pos..pos + 4];
+        let size = read_u32(data, pos + 4) as usize;
+        let end = (pos + 8 + size + size % 2).min(data.len());
+        if id == b"fmt " && size >= 8 && pos + 16 <= data.len(){
+            sample_rate = Some(read_u32(data, pos + 12));
+        }
+        if id != b"smpl" && id != b"cue "{
+            file.extend_from_slice(&data[pos..end]);
+        }
+        pos = end;
+    }
+    let sample_rate = sample_rate?;
+    // The pitch fraction says how sharp the sample is, the opposite of the tuning that
+    // corrects it. It can't be negative, so flat samples use the note below
+    let sharpness = -metadata.fine_tune;
+    let root_note = metadata.root_note.unwrap_or(60) as i32 + sharpness.div_euclid(100.0) as i32;
+    let pitch_fraction = (sharpness.rem_euclid(100.0) / 100.0 * 4294967296.0) as u64;
+    let mut smpl = Vec::new();
+    for word in [0, 0, 1_000_000_000 / sample_rate.max(1), root_note.clamp(0, 127) as u32,
+        pitch_fraction.min(u32::MAX as u64) as u32, 0, 0, metadata.sustain_loop.is_some() as u32, 0]{
+        smpl.extend_from_slice(&word.to_le_bytes());
+    }
+    if let Some(sample_loop) = metadata.sustain_loop{
+        let loop_type = match sample_loop.mode{
+            SustainModes::LoopBounce => 1,
+            _ => 0,
+        };
+        for word in [0, loop_type, sample_loop.start, sample_loop.end, 0, 0]{
+            smpl.extend_from_slice(&word.to_le_bytes());
+        }
+    }
+    let mut cue = (metadata.cues.len() as u32).to_le_bytes().to_vec();
+    for (id, offset) in metadata.cues.iter().enumerate(){
+        for word in [id as u32 + 1, *offset]{
+            cue.extend_from_slice(&word.to_le_bytes());
+        }
+        cue.extend_from_slice(b"data");
+        for word in [0, 0, *offset]{
+            cue.extend_from_slice(&word.to_le_bytes());
+        }
+    }
+    for (id, chunk) in [(b"smpl", smpl), (b"cue ", cue)]{
+        file.extend_from_slice(id);
+        file.extend_from_slice(&(chunk.len() as u32).to_le_bytes());
+        file.extend_from_slice(&chunk);
+    }
+    let riff_size = (file.len() - 8) as u32;
+    file[4..8].copy_from_slice(&riff_size.to_le_bytes());
+    Some(file)
+}
+
+/// Writes decoded audio as a 32 bit float wav, so no precision is lost
+pub fn encode_wav(audio: &DecodedAudio)->Result<Vec<u8>, hound::Error>{
+    let spec = hound::WavSpec{
+        channels: audio.num_channels as u16,
+        sample_rate: audio.sample_rate as u32,
+        bits_per_sample: 32,
+        sample_format: SampleFormat::Float,
+    };
+    let mut bytes = io::Cursor::new(Vec::new());
+    let mut writer = hound::WavWriter::new(&mut bytes, spec)?;
+    for sample in audio.samples.iter(){
+        writer.write_sample(*sample)?;
+    }
+    writer.finalize()?;
+    Ok(bytes.into_inner())
+}
+
+/// Returns the decoder for a file, going by its first bytes and only falling back to
+/// its extension if they aren't recognised
+pub fn find_decoder(path: &Path, header: &[u8])->Option<&'static dyn AudioDecoder>{
+    if let Some(decoder) = DECODERS.iter().find(|decoder| decoder.sniff(header)){
+        return Some(*decoder)
+    }
+    let extension = path.extension()?.to_string_lossy().to_lowercase();
+    DECODERS.iter().find(|decoder| decoder.extensions().contains(&extension.as_str())).copied()
+}
+
+/// Returns whether a file is in a format that can be decoded
+pub fn is_audio_file(path: &Path)->bool{
+    let mut header = Vec::with_capacity(SNIFF_LENGTH);
+    if let Ok(file) = fs::File::open(path){
+        let _ = file.take(SNIFF_LENGTH as u64).read_to_end(&mut header);
+    }
+    find_decoder(path, &header).is_some()
+}
+
+/// Decodes a file that has already been read into memory. `path` is only used
+/// for its extension if the format can't be sniffed, and for errors
+pub fn decode_bytes(path: &Path, data: Vec<u8>)->Result<DecodedAudio, LoadError>{
+    let path_buf = || path.to_path_buf();
+    if data.is_empty(){
+        return Err(LoadError::Empty{ path: path_buf() })
+    }
+    let decoder = find_decoder(path, &data[..data.len().min(SNIFF_LENGTH)])
+        .ok_or_else(|| LoadError::UnsupportedFormat{ path: path_buf() })?;
+    let decoded = decoder.decode(data).map_err(|error| match error{
+        DecodeError::UnsupportedBitDepth(bits) => LoadError::UnsupportedBitDepth{ path: path_buf(), bits },
+        DecodeError::Malformed(message) => LoadError::Malformed{ path: path_buf(), format: decoder.name(), message },
+    })?;
+    if decoded.num_channels == 0 || decoded.samples.len() < decoded.num_channels{
+        return Err(LoadError::Empty{ path: path_buf() })
+    }
+    Ok(decoded)
+}
+
+/// Reads and decodes an audio file in any supported format
+pub fn decode_file(path: &Path)->Result<DecodedAudio, LoadError>{
+    let data = fs::read(path).map_err(|error| LoadError::Io{ path: path.to_path_buf(), error })?;
+    decode_bytes(path, data)
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    /// Builds a 16 bit stereo AIFF file at 44.1kHz
+    fn aiff(frames: &[[i16; 2]])->Vec<u8>{
+        let mut comm = Vec::new();
+        comm.extend_from_slice(&2u16.to_be_bytes());
+        comm.extend_from_slice(&(frames.len() as u32).to_be_bytes());
+        comm.extend_from_slice(&16u16.to_be_bytes());
+        // 44100 as an 80 bit extended float
+        comm.extend_from_slice(&[0x40, 0x0e, 0xac, 0x44, 0, 0, 0, 0, 0, 0]);
+        let mut ssnd = vec![0; 8];
+        for frame in frames.iter(){
+            ssnd.extend_from_slice(&frame[0].to_be_bytes());
+            ssnd.extend_from_slice(&frame[1].to_be_bytes());
+        }
+        let mut body = b"AIFF".to_vec();
+        for (id, chunk) in [(b"COMM", comm), (b"SSND", ssnd)]{
+            body.extend_from_slice(id);
+            body.extend_from_slice(&(chunk.len() as u32).to_be_bytes());
+            body.extend_from_slice(&chunk);
+        }
+        let mut file = b"FORM".to_vec();
+        file.extend_from_slice(&(body.len() as u32).to_be_bytes());
+        file.extend_from_slice(&body);
+        file
+    }
+
+    #[test]
+    fn test_sniffing(){
+        let wav = Path::new("sample.wav");
+        assert_eq!(find_decoder(wav, b"RIFF\0\0\0\0WAVEfmt ").unwrap().name(), "WAV");
+        assert_eq!(find_decoder(wav, b"FORM\0\0\0\0AIFC").unwrap().name(), "AIFF");
+        assert_eq!(find_decoder(wav, b"fLaC\0\0\0\x22").unwrap().name(), "FLAC");
+        assert_eq!(find_decoder(wav, b"OggS\0\x02").unwrap().name(), "Ogg Vorbis");
+        assert_eq!(find_decoder(wav, b"ID3\x04").unwrap().name(), "MP3");
+        assert_eq!(find_decoder(wav, &[0xff, 0xfb, 0x90, 0x64]).unwrap().name(), "MP3");
+        // Unrecognised headers fall back to the extension, whatever its case
+        assert_eq!(find_decoder(Path::new("LOUD.FLAC"), &[]).unwrap().name(), "FLAC");
+        assert_eq!(find_decoder(Path::new("Kick.Aif"), &[]).unwrap().name(), "AIFF");
+        assert!(find_decoder(Path::new("notes.txt"), b"hello").is_none());
+    }
+    /// Writes a mono wav of the given format holding full scale negative, half scale
+    /// positive and silence
+    fn wav(bits: u16, sample_format: SampleFormat)->Vec<u8>{
+        let spec = hound::WavSpec{ channels: 1, sample_rate: 48000, bits_per_sample: bits, sample_format };
+        let mut bytes = io::Cursor::new(Vec::new());
+        let mut writer = hound::WavWriter::new(&mut bytes, spec).unwrap();
+        match sample_format{
+            SampleFormat::Float => {
+                for sample in [-1.0f32, 0.5, 0.0]{
+                    writer.write_sample(sample).unwrap();
+                }
+            },
+            SampleFormat::Int => {
+                let full_scale = 1i64 << (bits - 1);
+                for sample in [-full_scale, full_scale / 2, 0]{
+                    writer.write_sample(sample as i32).unwrap();
+                }
+            },
+        }
+        writer.finalize().unwrap();
+        bytes.into_inner()
+    }
+
+    #[test]
+    fn test_wav_bit_depths(){
+        for (bits, sample_format) in [(8, SampleFormat::Int), (16, SampleFormat::Int), (24, SampleFormat::Int),
+            (32, SampleFormat::Int), (32, SampleFormat::Float)]{
+            let decoded = decode_bytes(Path::new("depth.wav"), wav(bits, sample_format)).unwrap();
+            assert_eq!(decoded.samples, [-1.0, 0.5, 0.0], "{} bit {:?}", bits, sample_format);
+        }
+        // 8 bit samples are stored unsigned, 128 being silence
+        let eight_bit = wav(8, SampleFormat::Int);
+        assert_eq!(&eight_bit[eight_bit.len() - 3..], &[0, 192, 128]);
+    }
+    #[test]
+    fn test_load_errors(){
+        let missing = Path::new("/nonexistent/missing.wav");
+        assert!(matches!(decode_file(missing), Err(LoadError::Io{ .. })));
+        assert!(matches!(decode_bytes(missing, Vec::new()), Err(LoadError::Empty{ .. })));
+        assert!(matches!(decode_bytes(Path::new("notes.txt"), b"hello".to_vec()), Err(LoadError::UnsupportedFormat{ .. })));
+        assert!(matches!(decode_bytes(Path::new("broken.wav"), b"RIFF\0\0\0\0WAVEjunk".to_vec()), Err(LoadError::Malformed{ format: "WAV", .. })));
+        // A wav with no frames in it
+        let spec = hound::WavSpec{ channels: 2, sample_rate: 48000, bits_per_sample: 16, sample_format: SampleFormat::Int };
+        let mut no_frames = io::Cursor::new(Vec::new());
+        hound::WavWriter::new(&mut no_frames, spec).unwrap().finalize().unwrap();
+        assert!(matches!(decode_bytes(Path::new("empty.wav"), no_frames.into_inner()), Err(LoadError::Empty{ .. })));
+    }
+    /// Appends padded chunks to a wav file, fixing up its RIFF size
+    fn with_chunks(mut file: Vec<u8>, chunks: &[(&[u8; 4], Vec<u8>)])->Vec<u8>{
+        for (id, chunk) in chunks.iter(){
+            file.extend_from_slice(*id);
+            file.extend_from_slice(&(chunk.len() as u32).to_le_bytes());
+            file.extend_from_slice(chunk);
+            if chunk.len() % 2 == 1{
+                file.push(0);
+            }
+        }
+        let riff_size = (file.len() - 8) as u32;
+        file[4..8].copy_from_slice(&riff_size.to_le_bytes());
+        file
+    }
+
+    #[test]
+    fn test_wav_metadata(){
+        let words = |words: &[u32]| words.iter().flat_map(|word| word.to_le_bytes()).collect::<Vec<u8>>();
+        // Unity note 57 and a quarter of a semitone sharp, one ping-pong loop over frames 1 to 2
+        let smpl = words(&[0, 0, 0, 57, 1 << 30, 0, 0, 1, 0, 0, 1, 1, 2, 0, 0]);
+        let inst = vec![60, (-10i8) as u8, (-6i8) as u8, 48, 72, 1, 127];
+        let mut cue = words(&[2]);
+        for (id, offset) in [(1, 0), (2, 2)]{
+            cue.extend(words(&[id, 0]));
+            cue.extend_from_slice(b"data");
+            cue.extend(words(&[0, 0, offset]));
+        }
+        let file = with_chunks(wav(16, SampleFormat::Int), &[(b"smpl", smpl), (b"inst", inst), (b"cue ", cue)]);
+        let decoded = decode_bytes(Path::new("looped.wav"), file).unwrap();
+        assert_eq!(decoded.samples, [-1.0, 0.5, 0.0]);
+        let metadata = decoded.metadata;
+        // smpl's note and tuning win over inst's
+        assert_eq!(metadata.root_note, Some(57));
+        assert_eq!(metadata.fine_tune, -25.0);
+        assert_eq!(metadata.gain, -6.0);
+        assert_eq!(metadata.key_range, Some((48, 72)));
+        assert_eq!(metadata.velocity_range, Some((1, 127)));
+        assert_eq!(metadata.sustain_loop, Some(SampleLoop{ start: 1, end: 2, mode: SustainModes::LoopBounce }));
+        assert_eq!(metadata.cues, [0, 2]);
+        // Without smpl, inst supplies them
+        let file = with_chunks(wav(16, SampleFormat::Int), &[(b"inst", vec![60, (-10i8) as u8, 0, 0, 127, 0, 127])]);
+        let metadata = read_wav_metadata(&file);
+        assert_eq!((metadata.root_note, metadata.fine_tune, metadata.sustain_loop), (Some(60), -10.0, None));
+        assert_eq!(read_wav_metadata(&wav(16, SampleFormat::Int)), SampleMetadata::default());
+    }
+    #[test]
+    fn test_write_wav_metadata(){
+        let original = with_chunks(wav(16, SampleFormat::Int), &[(b"inst", vec![60, 0, 0, 0, 127, 0, 127]),
+            (b"smpl", vec![0; 36])]);
+        let metadata = SampleMetadata{
+            root_note: Some(62),
+            fine_tune: 25.0,
+            sustain_loop: Some(SampleLoop{ start: 0, end: 1, mode: SustainModes::LoopWrap }),
+            cues: vec![0, 3],
+            ..SampleMetadata::default()
+        };
+        let file = write_wav_metadata(&original, &metadata).unwrap();
+        // The fmt, data and inst chunks are untouched, the old smpl chunk is replaced
+        let inst_end = original.len() - 8 - 36;
+        assert_eq!(&file[12..inst_end], &original[12..inst_end]);
+        assert_eq!(read_u32(&file, 4) as usize, file.len() - 8);
+        let read = read_wav_metadata(&file);
+        assert_eq!(read.root_note, Some(61));
+        // Being 25 cents flat of 62 is written as 75 cents sharp of 61
+        assert!((read.fine_tune + 75.0).abs() < 0.0001);
+        assert_eq!((read.sustain_loop, read.cues), (metadata.sustain_loop, metadata.cues.clone()));
+        assert_eq!(decode_bytes(Path::new("looped.wav"), file).unwrap().samples, [-1.0, 0.5, 0.0]);
+        // Other formats are written out as float wavs first
+        assert!(write_wav_metadata(&aiff(&[[0, 0]]), &metadata).is_none());
+        let decoded = decode_bytes(Path::new("kick.aif"), aiff(&[[16384, -8192], [1, -1]])).unwrap();
+        let file = write_wav_metadata(&encode_wav(&decoded).unwrap(), &metadata).unwrap();
+        assert_eq!(decode_bytes(Path::new("kick.wav"), file).unwrap().samples, decoded.samples);
+    }
+    #[test]
+    fn test_decode_aiff(){
+        let frames: Vec<[i16; 2]> = (0..64).map(|i| [i * 256, -i * 256]).collect();
+        // Named as a wav, the header decides how it's decoded
+        let decoded = decode_bytes(Path::new("mislabelled.wav"), aiff(&frames)).unwrap();
+        assert_eq!(decoded.num_channels, 2);
+        assert_eq!(decoded.sample_rate, 44100.0);
+        assert_eq!(decoded.samples.len(), 128);
+        assert_eq!(decoded.samples[20], 10.0 * 256.0 / 32768.0);
+        assert_eq!(decoded.samples[21], -10.0 * 256.0 / 32768.0);
+    }
+}
@@ -0,0 +1,369 @@
+use crate::sample_pool::{SamplePool, PooledSample};
+use crate::sample_buffer::SampleBuffer;
+use crate::decoder::{decode_bytes, LoadError};
+use crate::sfz::{SfzInstrument, SfzRegion, LoopMode, Trigger, parse_note, resolve_sample_path};
+use std::{fmt, fs, io::{self, Read}, path::{Component, Path, PathBuf}};
+
+/// Why a Decent Sampler preset or library couldn't be loaded
+#[derive(Debug)]
+pub enum DsError{
+    Unreadable{ path: PathBuf, error: io::Error },
+    /// The XML couldn't be parsed
+    InvalidXml(String),
+    /// The root element isn't `<DecentSampler>`
+    NotAPreset,
+    /// The library isn't a zip archive, or has no `.dspreset` in it
+    InvalidLibrary(String),
+    /// None of the samples could be played. The warnings say why
+    NoRegions{ warnings: Vec<DsWarning> },
+}
+
+impl fmt::Display for DsError{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>)->fmt::Result{
+        match self{
+            DsError::Unreadable{ path, error } => write!(f, "Couldn't read {}: {}", path.display(), error),
+            DsError::InvalidXml(error) => write!(f, "The preset isn't valid XML: {}", error),
+            DsError::NotAPreset => write!(f, "The file isn't a Decent Sampler preset"),
+            DsError::InvalidLibrary(reason) => write!(f, "Not a valid Decent Sampler library: {}", reason),
+            DsError::NoRegions{ warnings } => write!(f, "The preset has no playable samples ({} warnings)", warnings.len()),
+        }
+    }
+}
+
+impl DsError{
+    /// Returns the warnings that explain why nothing could be played, if there are any
+    pub fn warnings(&self)->&[DsWarning]{
+        match self{
+            DsError::NoRegions{ warnings } => warnings,
+            _ => &[],
+        }
+    }
+}
+
+impl std::error::Error for DsError{}
+
+/// Something in a Decent Sampler preset that was skipped while loading it
+#[derive(Clone, Debug, PartialEq)]
+pub enum DsWarning{
+    /// The sample doesn't exist
+    MissingSample{ path: PathBuf },
+    /// The sample is in an unsupported format, is damaged or couldn't be read
+    UndecodableSample{ path: PathBuf, reason: String },
+    /// A `<sample>` element has no `path` attribute
+    NoPath,
+    InvalidValue{ attribute: String, value: String },
+}
+
+impl fmt::Display for DsWarning{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>)->fmt::Result{
+        match self{
+            DsWarning::MissingSample{ path } => write!(f, "Sample {} doesn't exist", path.display()),
+            DsWarning::UndecodableSample{ path, reason } => write!(f, "Sample {} couldn't be loaded: {}", path.display(), reason),
+            DsWarning::NoPath => write!(f, "A sample has no path"),
+            DsWarning::InvalidValue{ attribute, value } => write!(f, "{}=\"{}\" isn't a valid value", attribute, value),
+        }
+    }
+}
+
+/// Where the samples of a preset are read from
+enum SampleSource{
+    /// Sample paths are relative to the preset's folder
+    Folder(PathBuf),
+    /// Sample paths are relative to the preset's folder inside the archive
+    Library{ path: PathBuf, archive: zip::ZipArchive<fs::File>, preset_dir: PathBuf },
+}
+
+impl SampleSource{
+    /// Decodes a sample, or returns the copy already in the pool
+    fn load(&mut self, sample: &str, pool: &mut SamplePool)->Result<PooledSample, DsWarning>{
+        match self{
+            SampleSource::Folder(dir) => {
+                let path = resolve_sample_path(dir, sample);
+                pool.get_or_load(&path).map_err(|error| match error{
+                    LoadError::Io{ error, .. } if error.kind() == io::ErrorKind::NotFound => DsWarning::MissingSample{ path },
+                    error => DsWarning::UndecodableSample{ path, reason: error.to_string() },
+                })
+            },
+            SampleSource::Library{ path, archive, preset_dir } => {
+                let inner = normalize(&resolve_sample_path(preset_dir, sample));
+                let key = PathBuf::from(format!("{}#{}", path.display(), inner));
+                if let Some(sample) = pool.get(&key){
+                    return Ok(sample)
+                }
+                let undecodable = |reason: String| DsWarning::UndecodableSample{ path: PathBuf::from(&inner), reason };
+                let mut bytes = Vec::new();
+                let mut file = archive.by_name(&inner).map_err(|error| match error{
+                    zip::result::ZipError::FileNotFound => DsWarning::MissingSample{ path: PathBuf::from(&inner) },
+                    error => undecodable(error.to_string()),
+                })?;
+                file.read_to_end(&mut bytes).map_err(|error| undecodable(error.to_string()))?;
+                let decoded = decode_bytes(Path::new(&inner), bytes).map_err(|error| undecodable(error.to_string()))?;
+                let buffer = SampleBuffer::from_interleaved(&decoded.samples, decoded.num_channels);
+                Ok(pool.insert(&key, buffer, decoded.sample_rate, decoded.metadata.sustain_loop))
+            },
+        }
+    }
+}
+
+/// Resolves `..` and `.` in a path inside an archive, which has no folders to look up.
+/// Returns the name of the zip entry, which always uses `/` whatever the platform
+fn normalize(path: &Path)->String{
+    let mut normalized: Vec<String> = Vec::new();
+    for component in path.components(){
+        match component{
+            Component::ParentDir => {
+                normalized.pop();
+            },
+            Component::Normal(part) => normalized.push(part.to_string_lossy().into_owned()),
+            _ => (),
+        }
+    }
+    normalized.join("/")
+}
+
+/// Looks attributes up on a `<sample>`, then its `<group>`, then `<groups>`, so settings
+/// made higher up are inherited. Volume and tuning add up over the levels instead
+struct Attributes<'a, 'input>{
+    nodes: [roxmltree::Node<'a, 'input>; 3],
+    warnings: &'a mut Vec<DsWarning>,
+}
+
+impl Attributes<'_, '_>{
+    fn text(&self, name: &str)->Option<&str>{
+        self.nodes.iter().find_map(|node| node.attribute(name))
+    }
+    /// Parses an attribute, warning if it's set to something that can't be parsed
+    fn parse<T>(&mut self, name: &str, parse: impl Fn(&str)->Option<T>)->Option<T>{
+        let text = self.text(name)?;
+        let value = parse(text.trim());
+        if value.is_none(){
+            self.warnings.push(DsWarning::InvalidValue{ attribute: name.to_string(), value: text.to_string() });
+        }
+        value
+    }
+    fn float(&mut self, name: &str)->Option<f32>{
+        self.parse(name, |text| text.parse().ok())
+    }
+    /// Adds up an attribute over every level that sets it, for settings such as volume
+    /// that stack rather than override. Values that can't be parsed are warned about
+    /// and left out
+    fn sum(&mut self, name: &str, parse: impl Fn(&str)->Option<f32>)->Option<f32>{
+        let mut total = None;
+        for node in self.nodes.iter(){
+            let Some(text) = node.attribute(name) else {
+                continue
+            };
+            match parse(text.trim()){
+                Some(value) => total = Some(total.unwrap_or(0.0) + value),
+                None => self.warnings.push(DsWarning::InvalidValue{ attribute: name.to_string(), value: text.to_string() }),
+            }
+        }
+        total
+    }
+    fn note(&mut self, name: &str)->Option<u8>{
+        self.parse(name, parse_note)
+    }
+    fn frame(&mut self, name: &str)->Option<u32>{
+        self.parse(name, |text| text.parse::<f64>().ok().filter(|frame| *frame >= 0.0).map(|frame| frame as u32))
+    }
+}
+
+/// Parses a volume, given either in decibels like `-3dB` or as a linear gain
+fn parse_volume(text: &str)->Option<f32>{
+    let lower = text.to_lowercase();
+    if let Some(db) = lower.strip_suffix("db"){
+        return db.trim().parse().ok()
+    }
+    let gain: f32 = lower.parse().ok()?;
+    (gain >= 0.0).then(|| 20.0 * gain.max(1e-5).log10())
+}
+
+/// Builds the regions of a preset, one per `<sample>` element
+fn build_instrument(xml: &str, mut source: SampleSource)->Result<(SfzInstrument, Vec<DsWarning>), DsError>{
+    let document = roxmltree::Document::parse(xml).map_err(|error| DsError::InvalidXml(error.to_string()))?;
+    let root = document.root_element();
+    if !root.has_tag_name("DecentSampler"){
+        return Err(DsError::NotAPreset)
+    }
+    let mut pool = SamplePool::new();
+    let mut regions = Vec::new();
+    let mut warnings = Vec::new();
+    let all_groups = root.children().filter(|node| node.has_tag_name("groups"));
+    let groups = all_groups.flat_map(|groups| groups.children().filter(|node| node.has_tag_name("group")).map(move |group| (groups, group)));
+    for (group_idx, (groups, group)) in groups.enumerate(){
+        for sample in group.children().filter(|node| node.has_tag_name("sample")){
+            let mut attributes = Attributes{ nodes: [sample, group, groups], warnings: &mut warnings };
+            let Some(path) = sample.attribute("path") else {
+                attributes.warnings.push(DsWarning::NoPath);
+                continue
+            };
+            let pooled = match source.load(path, &mut pool){
+                Ok(pooled) => pooled,
+                Err(warning) => {
+                    attributes.warnings.push(warning);
+                    continue
+                },
+            };
+            let mut region = SfzRegion::new(pooled);
+            region.group = group_idx;
+            region.pitch_keycenter = attributes.note("rootNote");
+            region.lokey = attributes.note("loNote").unwrap_or(0);
+            region.hikey = attributes.note("hiNote").unwrap_or(127);
+            region.lovel = attributes.float("loVel").unwrap_or(0.0);
+            region.hivel = attributes.float("hiVel").unwrap_or(127.0);
+            region.offset = attributes.frame("start");
+            region.end = attributes.frame("end");
+            region.loop_start = attributes.frame("loopStart");
+            region.loop_end = attributes.frame("loopEnd");
+            region.loop_mode = attributes.parse("loopEnabled", |text| match text{
+                "true" => Some(LoopMode::LoopContinuous),
+                "false" => Some(LoopMode::NoLoop),
+                _ => None,
+            });
+            if let Some(volume) = attributes.sum("volume", parse_volume){
+                region.volume = volume;
+            }
+            if let Some(pan) = attributes.float("pan"){
+                region.pan = pan.clamp(-100.0, 100.0);
+            }
+            // Tuning is in semitones, the region's tune is in cents
+            if let Some(tuning) = attributes.sum("tuning", |text| text.parse().ok()){
+                region.tune = tuning * 100.0;
+            }
+            if let Some(veltrack) = attributes.float("ampVelTrack"){
+                region.amp_veltrack = veltrack * 100.0;
+            }
+            if attributes.text("trigger") == Some("release"){
+                region.trigger = Trigger::Release;
+            }
+            // Envelope times are in seconds and sustain is a level from 0 to 1
+            region.ampeg.attack = attributes.float("attack");
+            region.ampeg.decay = attributes.float("decay");
+            region.ampeg.sustain = attributes.float("sustain").map(|sustain| sustain * 100.0);
+            region.ampeg.release = attributes.float("release");
+            regions.push(region);
+        }
+    }
+    if regions.is_empty(){
+        return Err(DsError::NoRegions{ warnings })
+    }
+    Ok((SfzInstrument::new(regions, pool), warnings))
+}
+
+/// Loads a `.dspreset` file, whose samples sit next to it on disk
+pub fn load_dspreset(path: &Path)->Result<(SfzInstrument, Vec<DsWarning>), DsError>{
+    let xml = fs::read_to_string(path).map_err(|error| DsError::Unreadable{ path: path.to_path_buf(), error })?;
+    let dir = path.parent().unwrap_or(Path::new("")).to_path_buf();
+    build_instrument(&xml, SampleSource::Folder(dir))
+}
+
+/// Loads the first preset of a `.dslibrary`, a zip archive holding presets and their samples
+pub fn load_dslibrary(path: &Path)->Result<(SfzInstrument, Vec<DsWarning>), DsError>{
+    let file = fs::File::open(path).map_err(|error| DsError::Unreadable{ path: path.to_path_buf(), error })?;
+    let mut archive = zip::ZipArchive::new(file).map_err(|error| DsError::InvalidLibrary(error.to_string()))?;
+    let mut presets: Vec<String> = archive.file_names()
+        .filter(|name| name.to_lowercase().ends_with(".dspreset"))
+        .map(|name| name.to_string())
+        .collect();
+    presets.sort();
+    let preset = presets.first().ok_or(DsError::InvalidLibrary("there are no presets in it".to_string()))?.clone();
+    let mut xml = String::new();
+    archive.by_name(&preset)
+        .map_err(|error| DsError::InvalidLibrary(error.to_string()))?
+        .read_to_string(&mut xml)
+        .map_err(|error| DsError::Unreadable{ path: path.join(&preset), error })?;
+    let preset_dir = Path::new(&preset).parent().unwrap_or(Path::new("")).to_path_buf();
+    build_instrument(&xml, SampleSource::Library{ path: path.to_path_buf(), archive, preset_dir })
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+    use std::io::Write;
+
+    fn tone(frames: usize)->Vec<u8>{
+        let spec = hound::WavSpec{
+            channels: 1,
+            sample_rate: 48000,
+            bits_per_sample: 16,
+            sample_format: hound::SampleFormat::Int,
+        };
+        let mut bytes = io::Cursor::new(Vec::new());
+        let mut writer = hound::WavWriter::new(&mut bytes, spec).unwrap();
+        for i in 0..frames{
+            writer.write_sample((i * 10) as i16).unwrap();
+        }
+        writer.finalize().unwrap();
+        bytes.into_inner()
+    }
+
+    const PRESET: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+        <DecentSampler minVersion="1.0.0">
+          <groups attack="0.01" release="0.5" tuning="0.5">
+            <group sustain="0.5" volume="-6dB" tuning="-2">
+              <sample path="Samples/low.wav" rootNote="48" loNote="0" hiNote="59" loopStart="10" loopEnd="90" loopEnabled="true" volume="-3dB"/>
+              <sample path="Samples\high.wav" rootNote="C4" loNote="60" hiNote="127" loVel="64" hiVel="127" start="5" end="150" release="2"/>
+            </group>
+            <group>
+              <sample path="Samples/missing.wav" rootNote="60"/>
+              <sample path="Samples/broken.wav" rootNote="60"/>
+              <sample rootNote="abc" path="Samples/low.wav"/>
+            </group>
+          </groups>
+        </DecentSampler>"#;
+
+    fn check_regions(instrument: &SfzInstrument, warnings: &[DsWarning]){
+        assert_eq!(instrument.regions.len(), 3);
+        let low = &instrument.regions[0];
+        assert_eq!((low.lokey, low.hikey, low.pitch_keycenter), (0, 59, Some(48)));
+        assert_eq!((low.loop_start, low.loop_end, low.loop_mode), (Some(10), Some(90), Some(LoopMode::LoopContinuous)));
+        assert_eq!(low.sample.buffer.capacity(), 100);
+        // Envelope settings are inherited from the group and groups
+        assert_eq!((low.ampeg.attack, low.ampeg.sustain, low.ampeg.release), (Some(0.01), Some(50.0), Some(0.5)));
+        // Volume and tuning stack up over the sample, group and groups
+        assert_eq!((low.volume, low.tune), (-9.0, -150.0));
+        let high = &instrument.regions[1];
+        assert_eq!((high.lokey, high.pitch_keycenter, high.lovel), (60, Some(60), 64.0));
+        assert_eq!((high.offset, high.end), (Some(5), Some(150)));
+        assert_eq!(high.ampeg.release, Some(2.0));
+        assert_eq!(high.sample.buffer.capacity(), 200);
+        assert_eq!(instrument.regions[2].group, 1);
+        assert!(matches!(warnings[0], DsWarning::MissingSample{ .. }));
+        // A damaged sample says why it couldn't be loaded
+        assert!(matches!(&warnings[1], DsWarning::UndecodableSample{ reason, .. } if !reason.is_empty()));
+        assert_eq!(warnings[2], DsWarning::InvalidValue{ attribute: "rootNote".to_string(), value: "abc".to_string() });
+    }
+
+    #[test]
+    fn test_dspreset(){
+        let dir = std::env::temp_dir().join("rust_sampler_dspreset_test");
+        fs::create_dir_all(dir.join("Samples")).unwrap();
+        fs::write(dir.join("Samples").join("low.wav"), tone(100)).unwrap();
+        fs::write(dir.join("Samples").join("high.wav"), tone(200)).unwrap();
+        fs::write(dir.join("Samples").join("broken.wav"), b"RIFF\x04\x00\x00\x00WAVE").unwrap();
+        fs::write(dir.join("test.dspreset"), PRESET).unwrap();
+        let (instrument, warnings) = load_dspreset(&dir.join("test.dspreset")).unwrap();
+        check_regions(&instrument, &warnings);
+        assert!(matches!(build_instrument("<sfz/>", SampleSource::Folder(dir.clone())), Err(DsError::NotAPreset)));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+    #[test]
+    fn test_dslibrary(){
+        let path = std::env::temp_dir().join("rust_sampler_test.dslibrary");
+        let mut writer = zip::ZipWriter::new(fs::File::create(&path).unwrap());
+        let options = zip::write::SimpleFileOptions::default().compression_method(zip::CompressionMethod::Stored);
+        for (name, data) in [
+            ("Instrument/test.dspreset", PRESET.as_bytes().to_vec()),
+            ("Instrument/Samples/low.wav", tone(100)),
+            ("Instrument/Samples/high.wav", tone(200)),
+            ("Instrument/Samples/broken.wav", b"RIFF\x04\x00\x00\x00WAVE".to_vec()),
+        ]{
+            writer.start_file(name, options).unwrap();
+            writer.write_all(&data).unwrap();
+        }
+        writer.finish().unwrap();
+        let (instrument, warnings) = load_dslibrary(&path).unwrap();
+        check_regions(&instrument, &warnings);
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -2,27 +2,67 @@ use nih_plug::prelude::*;
 use nih_plug_egui::{create_egui_editor, egui, widgets, EguiState};
 mod adsr;
 mod ring_buffer;
+mod sample_buffer;
 mod sampler_voice;
 mod sampler_engine;
 mod crossfade;
-use sampler_engine::{SamplerEngine,SamplerMode};
+mod sample_pool;
+mod sfz;
+mod sf2;
+mod dspreset;
+mod decoder;
+mod pitch;
+use sampler_engine::{SamplerEngine,SamplerMode,EngineEvent,WarpParams,WarpExport,LoadedInstrument};
 use sampler_voice::SustainModes;
-use egui::{ColorImage, ImageData, TextureHandle, TextureOptions, Context as EguiContext, Color32};
-use image::{DynamicImage, GenericImageView, ImageFormat, RgbaImage};
-use nih_plug::prelude::*;
-use egui::epaint::{PathShape, Pos2, Stroke, Rect};
-use std::{fs, io::Seek};
+use ring_buffer::Interpolation;
+use sf2::PresetNumber;
+use egui::{ImageData, TextureOptions, Color32};
+use image::GenericImageView;
+use egui::epaint::{PathShape, Pos2, Stroke};
 use egui_file::FileDialog;
 use homedir::get_my_home;
-use std::{path::PathBuf, sync::{Arc, Mutex}};
-use std::env::current_dir;
+use std::{path::{Path, PathBuf}, sync::{Arc, Mutex, atomic::{AtomicBool, AtomicI32, Ordering}}};
+
 
+/// The number of note events that can be queued for a single block without allocating.
+/// Blocks with more are rendered in pieces
+const MAX_BLOCK_EVENTS: usize = 1024;
+/// The number of samples rendered between steps of the smoothed parameters
+const CONTROL_BLOCK: usize = 32;
+/// The number of loaded files that can wait to be swapped in, or to be freed, at once
+const LOAD_QUEUE_SIZE: usize = 8;
 
 struct RustSampler {
     params: Arc<RustSamplerParams>,
     engine: Option<SamplerEngine>,  
     file_dialog: Arc<Mutex<FileDialog>>,
+    /// Picks where the sample is exported to, with its loop embedded
+    export_dialog: Arc<Mutex<FileDialog>>,
     file_path: Arc<FilePaths>,
+    articulations: Arc<Articulations>,
+    events: Vec<EngineEvent>,
+    /// The SoundFont preset that was last loaded, so a change can be picked up
+    sf2_preset: PresetNumber,
+    /// Files decoded on the background thread, waiting to be swapped into the engine
+    loaded_consumer: rtrb::Consumer<LoadedInstrument>,
+    loaded_producer: Arc<Mutex<rtrb::Producer<LoadedInstrument>>>,
+    /// Files swapped out of the engine, waiting to be freed on the background thread
+    garbage_producer: rtrb::Producer<LoadedInstrument>,
+    garbage_consumer: Arc<Mutex<rtrb::Consumer<LoadedInstrument>>>,
+}
+
+/// Work that is done on the background thread so the audio thread never touches
+/// the file system or frees sample memory
+pub enum LoadTask {
+    /// Decode the instrument or sample file at the given path
+    Load(String),
+    /// Load the current file again if it's a SoundFont, after the preset changed
+    ReloadPreset,
+    /// Free the files that have been swapped out of the engine
+    CollectGarbage,
+    /// Write the current sample to the chosen export path, with the warp sampler's
+    /// root note and loop embedded
+    Export(WarpExport),
 }
 
 #[derive(Params)]
@@ -59,15 +99,44 @@ struct RustSamplerParams {
     pub sus_mode: EnumParam<SustainModes>,
     #[id = "fade_time"]
     pub fade_time: FloatParam,
+    /// Interpolation used while playing back in realtime
+    #[id = "quality"]
+    pub quality: EnumParam<Interpolation>,
+    /// Interpolation used while the host renders offline
+    #[id = "render_quality"]
+    pub render_quality: EnumParam<Interpolation>,
+    /// Seed for the random SFZ region selection, so renders can be repeated
+    #[id = "random_seed"]
+    pub random_seed: IntParam,
+    /// The MIDI bank of the sf2 preset that is played
+    #[id = "sf2_bank"]
+    pub sf2_bank: IntParam,
+    /// The MIDI program of the sf2 preset that is played
+    #[id = "sf2_program"]
+    pub sf2_program: IntParam,
+    /// The note the warp sample is based on, or -1 to use the one from its file or
+    /// pitch detection
+    #[id = "root_note"]
+    pub root_note: IntParam,
 }
 
 impl Default for RustSampler {
     fn default() -> Self {
+        let (loaded_producer, loaded_consumer) = rtrb::RingBuffer::new(LOAD_QUEUE_SIZE);
+        let (garbage_producer, garbage_consumer) = rtrb::RingBuffer::new(LOAD_QUEUE_SIZE);
         Self {
             params: Arc::new(RustSamplerParams::default()),
             file_dialog: Arc::new(Mutex::new(FileDialog::open_file(get_my_home().unwrap()))),
+            export_dialog: Arc::new(Mutex::new(FileDialog::save_file(get_my_home().unwrap()))),
             engine: None,
             file_path: Arc::new(FilePaths::new()),
+            articulations: Arc::new(Articulations::new()),
+            events: Vec::new(),
+            sf2_preset: PresetNumber::default(),
+            loaded_consumer,
+            loaded_producer: Arc::new(Mutex::new(loaded_producer)),
+            garbage_producer,
+            garbage_consumer: Arc::new(Mutex::new(garbage_consumer)),
             }
     }
 }
@@ -168,7 +237,43 @@ impl Default for RustSamplerParams {
                 .with_smoother(SmoothingStyle::Linear(20.0))
                 .with_unit("ms")
                 .with_step_size(1.0),
-
+            quality: EnumParam::new(
+                "Quality",
+                Interpolation::Cubic,
+            ),
+            render_quality: EnumParam::new(
+                "Render Quality",
+                Interpolation::Sinc,
+            ),
+            random_seed: IntParam::new(
+                "Random Seed",
+                0,
+                IntRange::Linear { min: 0, max: 9999 }
+            ),
+            // Bank select is a 14 bit MIDI value, GM percussion sits in bank 128
+            sf2_bank: IntParam::new(
+                "SF2 Bank",
+                0,
+                IntRange::Linear { min: 0, max: 16383 }
+            ),
+            sf2_program: IntParam::new(
+                "SF2 Program",
+                0,
+                IntRange::Linear { min: 0, max: 127 }
+            ),
+            root_note: IntParam::new(
+                "Root Note",
+                -1,
+                IntRange::Linear { min: -1, max: 127 }
+            )
+            .with_value_to_string(Arc::new(|note| match u8::try_from(note) {
+                Ok(note) => sfz::note_name(note),
+                Err(_) => String::from("Auto"),
+            }))
+            .with_string_to_value(Arc::new(|string| match string.trim() {
+                auto if auto.eq_ignore_ascii_case("auto") => Some(-1),
+                note => sfz::parse_note(note).map(i32::from),
+            })),
         }
     }
 }
@@ -197,7 +302,8 @@ impl Plugin for RustSampler {
     }];
 
 
-    const MIDI_INPUT: MidiConfig = MidiConfig::Basic;
+    // CCs are needed for the locc/hicc conditions of SFZ files
+    const MIDI_INPUT: MidiConfig = MidiConfig::MidiCCs;
     const MIDI_OUTPUT: MidiConfig = MidiConfig::None;
 
     const SAMPLE_ACCURATE_AUTOMATION: bool = true;
@@ -206,20 +312,59 @@ impl Plugin for RustSampler {
     // messages here. The type implements the `SysExMessage` trait, which allows conversion to and
     // from plain byte buffers.
     type SysExMessage = ();
-    // More advanced plugins can use this to run expensive background tasks. See the field's
-    // documentation for more information. `()` means that the plugin does not have any background
-    // tasks.
-    type BackgroundTask = ();
+    // Decoding files happens on a background thread, see `task_executor()`
+    type BackgroundTask = LoadTask;
 
     fn params(&self) -> Arc<dyn Params> {
         self.params.clone()
     }
 
+    fn task_executor(&mut self) -> TaskExecutor<Self> {
+        let loaded_producer = self.loaded_producer.clone();
+        let garbage_consumer = self.garbage_consumer.clone();
+        let articulations = self.articulations.clone();
+        let file_path = self.file_path.clone();
+        let params = self.params.clone();
+        Box::new(move |task| {
+            // Anything swapped out since the last task is freed here, off the audio thread
+            while let Ok(old) = garbage_consumer.lock().unwrap().pop() {
+                drop(old);
+            }
+            let path = match task {
+                LoadTask::Load(path) => path,
+                LoadTask::ReloadPreset => match file_path.get_path() {
+                    Some(path) if is_sf2(&path) => path,
+                    _ => return,
+                },
+                LoadTask::CollectGarbage => return,
+                LoadTask::Export(export) => {
+                    if let (Some(source), Some(destination)) = (file_path.get_path(), file_path.get_export_path()) {
+                        let message = match sampler_engine::export_warp_sample(&source, &destination, &export) {
+                            Ok(()) => format!("Exported to {}", destination.display()),
+                            Err(error) => error,
+                        };
+                        nih_log!("{}", message);
+                        file_path.set_export_message(message);
+                    }
+                    return;
+                }
+            };
+            if let Some(loaded) = load_and_report(&path, sf2_preset(&params), &file_path) {
+                articulations.set_names(&loaded);
+                if loaded_producer.lock().unwrap().push(loaded).is_err() {
+                    nih_log!("Too many files waiting to be loaded, skipping {}", path);
+                }
+            }
+        })
+    }
+
     
-    fn editor(&mut self, _async_executor: AsyncExecutor<Self>) -> Option<Box<dyn Editor>> {
+    fn editor(&mut self, async_executor: AsyncExecutor<Self>) -> Option<Box<dyn Editor>> {
         let params = self.params.clone();
         let file_dialog = self.file_dialog.clone();
-        let mut file_path = self.file_path.clone();
+        let export_dialog = self.export_dialog.clone();
+        let file_path = self.file_path.clone();
+        let articulations = self.articulations.clone();
         create_egui_editor(
             self.params.editor_state.clone(),
             (),
@@ -301,10 +446,53 @@ impl Plugin for RustSampler {
                         start_text = path.clone();
                     }
                     ui.label(start_text);
-                    if (ui.button("Open")).clicked() {
+                    let report = file_path.get_report();
+                    if let Some(error) = &report.error {
+                        ui.colored_label(Color32::RED, error);
+                    }
+                    if !report.warnings.is_empty() {
+                        ui.collapsing(format!("Load warnings ({})", report.warnings.len()), |ui| {
+                            egui::ScrollArea::vertical().max_height(120.0).show(ui, |ui| {
+                                for warning in report.warnings.iter() {
+                                    ui.label(warning);
+                                }
+                            });
+                        });
+                    }
+                    if let Some(name) = articulations.active_name() {
+                        ui.label(format!("Articulation: {}", name));
+                    }
+                    if ui.button("Open").clicked() {
                         file_dialog.lock().unwrap().open();
                     }
-                    /// ADSR
+                    if let Some(path) = file_path.get_path().filter(|path| is_sample(path)) {
+                        if ui.button("Export WAV").clicked() {
+                            // Start in the sample's folder, next to the original
+                            let path = Path::new(&path);
+                            let name = format!("{} looped.wav", path.file_stem().unwrap_or_default().to_string_lossy());
+                            let mut dialog = FileDialog::save_file(path.parent().map(Path::to_path_buf))
+                                .default_filename(name);
+                            dialog.open();
+                            *export_dialog.lock().unwrap() = dialog;
+                        }
+                        if let Some(message) = file_path.get_export_message() {
+                            ui.label(message);
+                        }
+                    }
+                    ui.label("Root Note");
+                    ui.add(widgets::ParamSlider::for_param(&params.root_note, setter));
+                    if file_path.get_path().is_some_and(|path| is_sf2(&path)) {
+                        ui.label("Bank");
+                        ui.add(widgets::ParamSlider::for_param(&params.sf2_bank, setter));
+                        ui.horizontal(|ui| {
+                            ui.label("Program");
+                            ui.add(widgets::ParamSlider::for_param(&params.sf2_program, setter));
+                            if let Some(name) = &report.name {
+                                ui.label(name);
+                            }
+                        });
+                    }
+                    // ADSR
                     ui.label("Attack");
                     ui.add(widgets::ParamSlider::for_param(&params.attack, setter));
                     ui.label("Decay");
@@ -340,10 +528,10 @@ impl Plugin for RustSampler {
 
 
                     // Handle the num_voices slider
-                    let mut num_voices = params.num_voices.value() as i32;  // Casting to i32 for the slider
+                    let mut num_voices = params.num_voices.value();
                     let num_voices_slider = egui::Slider::new(&mut num_voices, 1..=24).text("Number of Voices");
                     if ui.add(num_voices_slider).changed() {
-                        setter.set_parameter(&params.num_voices, num_voices as i32);  // Cast back to i32 if needed
+                        setter.set_parameter(&params.num_voices, num_voices);
                     }
 
                     // Handle the sus_start slider
@@ -372,6 +560,22 @@ impl Plugin for RustSampler {
                         }
                     });
                     ui.end_row();
+                    ui.label("Quality");
+                    ui.horizontal(|ui| {
+                        let mut selected_q = params.quality.value();
+                        interpolation_selector(ui, &mut selected_q);
+                        if selected_q != params.quality.value() {
+                            setter.set_parameter(&params.quality, selected_q)
+                        }
+                    });
+                    ui.label("Render Quality");
+                    ui.horizontal(|ui| {
+                        let mut selected_q = params.render_quality.value();
+                        interpolation_selector(ui, &mut selected_q);
+                        if selected_q != params.render_quality.value() {
+                            setter.set_parameter(&params.render_quality, selected_q)
+                        }
+                    });
                     // Handle the fade_time slider
                     let mut fade_time = params.fade_time.value();
                     let fade_time_slider = egui::Slider::new(&mut fade_time, 0.0..=500.0).text("Crossfade Time (ms)");
@@ -408,10 +612,18 @@ impl Plugin for RustSampler {
                 }); 
                 if file_dialog.lock().unwrap().show(egui_ctx).selected() {
                     if let Some(file) = file_dialog.lock().unwrap().path() {
-                        file_path.set_path(String::from(file.to_str().unwrap()));
-                        dbg!(Some(file.to_path_buf()));
+                        let path = String::from(file.to_str().unwrap());
+                        file_path.set_path(path.clone());
+                        async_executor.execute_background(LoadTask::Load(path));
                     }
                 } 
+                // The audio thread fills in where the sample is played from, then hands
+                // the export to the background thread
+                if export_dialog.lock().unwrap().show(egui_ctx).selected() {
+                    if let Some(file) = export_dialog.lock().unwrap().path() {
+                        file_path.request_export(file.to_path_buf());
+                    }
+                }
             },
         )
     }
@@ -430,21 +642,24 @@ impl Plugin for RustSampler {
         self.engine = Some(engine_);
 
         self.engine.as_mut().unwrap().set_mode(SamplerMode::Warp);
-        self.engine.as_mut().unwrap().set_warp_base(60);
+        self.engine.as_mut().unwrap().set_root_override(root_override(&self.params));
+        // We're not on the audio thread yet, so a previously chosen file can be decoded directly
+        self.sf2_preset = sf2_preset(&self.params);
+        if let Some(path) = self.file_path.get_path() {
+            if let Some(loaded) = load_and_report(&path, self.sf2_preset, &self.file_path) {
+                self.articulations.set_names(&loaded);
+                self.engine.as_mut().unwrap().swap_instrument(loaded);
+            }
+        }
+        // Reserve room for the note events of a block so the audio thread doesn't allocate
+        self.events.reserve(MAX_BLOCK_EVENTS);
         true
     }
 
     fn reset(&mut self) {
-        // Reset buffers and envelopes here. This can be called from the audio thread and may not
-        // allocate. You can remove this function if you do not need it.
-        if let Some(path) = self.file_path.get_path(){
-            if path.ends_with(".wav"){
-                self.engine.as_mut().unwrap().set_mode(SamplerMode::Warp);
-                self.engine.as_mut().unwrap().load_file_from_path(&path);
-            }else if path.ends_with(".sfz"){
-                self.engine.as_mut().unwrap().load_sfz(path.as_str());
-                self.engine.as_mut().unwrap().set_mode(SamplerMode::Sfz);
-            }
+        // Start over so each render of a project plays the same regions
+        if let Some(engine) = self.engine.as_mut() {
+            engine.reset(self.params.random_seed.value() as u64);
         }
     }
 
@@ -454,48 +669,65 @@ impl Plugin for RustSampler {
         _aux: &mut AuxiliaryBuffers,
         context: &mut impl ProcessContext<Self>,
     ) -> ProcessStatus {
-        let mut next_event = context.next_event();
-        
-        if self.file_path.is_new_file_loaded(){
-            self.file_path.clear_new_file_flag();
-            self.reset();
+        // Swap in any files that finished loading. The old contents are handed back to the
+        // background thread to be freed there
+        while let Ok(loaded) = self.loaded_consumer.pop() {
+            let old = self.engine.as_mut().unwrap().swap_instrument(loaded);
+            if self.garbage_producer.push(old).is_err() {
+                nih_debug_assert_failure!("Garbage queue is full, freeing on the audio thread");
+            }
+            context.execute_background(LoadTask::CollectGarbage);
         }
-        for channel_samples in buffer.iter_samples() {
-            // Smoothing is optionally built into the parameters themselves
-            // TODO: Find out why no audio... not getting midi messages
-            while let Some(event) = next_event{
-                match event{
-                    NoteEvent::NoteOn { note, velocity, .. } => {
-                        self.engine.as_mut().unwrap().note_on(note, velocity);
-                    }
-                    NoteEvent::NoteOff { note, .. } => {
-                        self.engine.as_mut().unwrap().note_off(note);
-                    }
-                    _ => (),
+        if self.file_path.take_export_request() {
+            let export = self.engine.as_mut().unwrap().warp_export();
+            context.execute_background(LoadTask::Export(export));
+        }
+        // Picking another SoundFont preset means building a new instrument off the audio thread
+        let preset = sf2_preset(&self.params);
+        if preset != self.sf2_preset {
+            self.sf2_preset = preset;
+            context.execute_background(LoadTask::ReloadPreset);
+        }
+        // Offline renders can afford a more expensive interpolation kernel
+        let interpolation = match context.process_mode(){
+            ProcessMode::Offline => self.params.render_quality.value(),
+            _ => self.params.quality.value(),
+        };
+        self.engine.as_mut().unwrap().set_root_override(root_override(&self.params));
+        let num_samples = buffer.samples();
+        let outputs = buffer.as_slice();
+        let mut rendered = 0;
+        self.events.clear();
+        while let Some(event) = context.next_event(){
+            let event = match event{
+                NoteEvent::NoteOn { timing, channel, note, velocity, .. } => {
+                    EngineEvent::NoteOn { timing, channel, note, velocity }
+                }
+                NoteEvent::NoteOff { timing, channel, note, .. } => {
+                    EngineEvent::NoteOff { timing, channel, note }
+                }
+                NoteEvent::MidiCC { timing, channel, cc, value } => {
+                    EngineEvent::ControlChange { timing, channel, cc, value }
                 }
-                next_event = context.next_event();
+                _ => continue,
+            };
+            // Rather than growing the queue on the audio thread, a full one is played
+            // up to this event and emptied
+            if self.events.len() == MAX_BLOCK_EVENTS {
+                let until = (event.timing() as usize).clamp(rendered, num_samples);
+                self.render_range(outputs, rendered, until, interpolation);
+                rendered = until;
+                self.events.clear();
             }
+            self.events.push(event);
+        }
+        self.render_range(outputs, rendered, num_samples, interpolation);
+        self.articulations.set_active(self.engine.as_mut().unwrap().active_keyswitch());
 
+        for channel_samples in buffer.iter_samples() {
+            // Smoothing is optionally built into the parameters themselves
+            let gain = self.params.gain.smoothed.next();
             for sample in channel_samples {
-                let gain = self.params.gain.smoothed.next();
-                let attack = self.params.attack.smoothed.next()*0.001;
-                let decay = self.params.decay.smoothed.next()*0.001;
-                let sustain = self.params.sustain.smoothed.next();
-                let release = self.params.release.smoothed.next()*0.001;
-                let num_voices = self.params.num_voices.value();
-                let start = self.params.start_point.smoothed.next();
-                let end = self.params.end_point.smoothed.next();
-                let sus_start = self.params.sus_start.smoothed.next();
-                let sus_end = self.params.sus_end.smoothed.next();
-                let sus_mode = self.params.sus_mode.value();
-                let fade_time = self.params.fade_time.value()*0.001;
-                self.engine.as_mut().unwrap().set_num_voices(num_voices as u8);
-                self.engine.as_mut().unwrap().set_adsr_warp(attack, decay, sustain, release);
-                self.engine.as_mut().unwrap().set_points_warp(start, end);
-                self.engine.as_mut().unwrap().set_sus_looping_warp(sus_mode);
-                self.engine.as_mut().unwrap().set_sus_points_warp(sus_start, sus_end);
-                self.engine.as_mut().unwrap().set_fade_time_warp(fade_time);
-                *sample = self.engine.as_mut().unwrap().process();
                 *sample *= gain;
             }
         }
@@ -504,40 +736,229 @@ impl Plugin for RustSampler {
     }
 }
 
+impl RustSampler {
+    /// Renders samples `start..end` of the block with the queued events. It's played in
+    /// control blocks, stepping the smoothed parameters in between so automating them
+    /// doesn't jump. Parameters are only passed on to the voices when they change
+    fn render_range(&mut self, outputs: &mut [&mut [f32]], start: usize, end: usize, interpolation: Interpolation) {
+        let engine = self.engine.as_mut().unwrap();
+        let params = &self.params;
+        let mut next_event = 0;
+        let mut block_start = start;
+        loop {
+            let block_end = (block_start + CONTROL_BLOCK).min(end);
+            let steps = (block_end - block_start) as u32;
+            engine.set_warp_params(WarpParams {
+                num_voices: params.num_voices.value() as u8,
+                attack: params.attack.smoothed.next_step(steps)*0.001,
+                decay: params.decay.smoothed.next_step(steps)*0.001,
+                sustain: params.sustain.smoothed.next_step(steps),
+                release: params.release.smoothed.next_step(steps)*0.001,
+                start_point: params.start_point.smoothed.next_step(steps),
+                end_point: params.end_point.smoothed.next_step(steps),
+                sus_start: params.sus_start.smoothed.next_step(steps),
+                sus_end: params.sus_end.smoothed.next_step(steps),
+                sus_mode: params.sus_mode.value(),
+                fade_time: params.fade_time.value()*0.001,
+                interpolation,
+            });
+            // The last control block also plays any events at or past the end
+            let events = &self.events[next_event..];
+            let count = match block_end == end {
+                true => events.len(),
+                false => events.iter().take_while(|event| (event.timing() as usize) < block_end).count(),
+            };
+            engine.render_range(outputs, &events[..count], block_start, block_end);
+            next_event += count;
+            if block_end == end {
+                break;
+            }
+            block_start = block_end;
+        }
+    }
+}
+
+/// Draws one selectable button per interpolation kernel
+fn interpolation_selector(ui: &mut egui::Ui, selected: &mut Interpolation) {
+    ui.selectable_value(selected, Interpolation::Linear, "Linear");
+    ui.selectable_value(selected, Interpolation::Cubic, "Cubic");
+    ui.selectable_value(selected, Interpolation::Lagrange, "Lagrange");
+    ui.selectable_value(selected, Interpolation::Sinc, "Sinc");
+}
+
+/// Returns whether a path is a SoundFont, whose preset can be changed
+fn is_sf2(path: &str) -> bool {
+    path.to_lowercase().ends_with(".sf2")
+}
+
+/// Returns the SoundFont preset picked by the bank and program parameters
+fn sf2_preset(params: &RustSamplerParams) -> PresetNumber {
+    PresetNumber {
+        bank: params.sf2_bank.value() as u16,
+        program: params.sf2_program.value() as u16,
+    }
+}
+
+/// Returns the root note chosen by hand, if it isn't left on "Auto"
+fn root_override(params: &RustSamplerParams) -> Option<u8> {
+    u8::try_from(params.root_note.value()).ok()
+}
+
+/// Returns whether a path looks like a sample rather than an instrument, going by
+/// its extension so the editor doesn't read the file every frame
+fn is_sample(path: &str) -> bool {
+    decoder::find_decoder(Path::new(path), &[]).is_some()
+}
+
+/// Loads a file on the background thread, recording its warnings or the reason it
+/// couldn't be loaded so the editor can show them
+fn load_and_report(path: &str, sf2_preset: PresetNumber, file_path: &FilePaths) -> Option<LoadedInstrument> {
+    match sampler_engine::load_instrument(path, sf2_preset) {
+        Ok((loaded, warnings)) => {
+            let name = match &loaded {
+                LoadedInstrument::Sfz(instrument) => instrument.name.clone(),
+                _ => None,
+            };
+            file_path.set_report(LoadReport { error: None, warnings, name });
+            Some(loaded)
+        }
+        Err(error) => {
+            nih_log!("Couldn't load {}: {}", path, error);
+            file_path.set_report(LoadReport { error: Some(error), ..LoadReport::default() });
+            None
+        }
+    }
+}
+
+/// What happened the last time a file was loaded
+#[derive(Clone, Default)]
+pub struct LoadReport {
+    /// Why the file couldn't be loaded, if it couldn't
+    pub error: Option<String>,
+    /// Anything in the file that had to be skipped
+    pub warnings: Vec<String>,
+    /// The name the file gives the instrument, such as a SoundFont preset's
+    pub name: Option<String>,
+}
+
 pub struct FilePaths {
     path: Mutex<Option<String>>,
-    new_file_loaded: Mutex<bool>,
+    report: Mutex<LoadReport>,
+    /// Where the sample is to be exported to
+    export_path: Mutex<Option<PathBuf>>,
+    /// Set by the editor, taken by the audio thread once it's ready to export
+    export_requested: AtomicBool,
+    /// How the last export went
+    export_message: Mutex<Option<String>>,
+}
+
+impl Default for FilePaths {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl FilePaths {
     pub fn new() -> Self {
         Self {
             path: Mutex::new(None),
-            new_file_loaded: Mutex::new(false),
+            report: Mutex::new(LoadReport::default()),
+            export_path: Mutex::new(None),
+            export_requested: AtomicBool::new(false),
+            export_message: Mutex::new(None),
         }
     }
 
+    pub fn set_report(&self, report: LoadReport) {
+        *self.report.lock().unwrap() = report;
+    }
+
+    pub fn get_report(&self) -> LoadReport {
+        self.report.lock().unwrap().clone()
+    }
+
     pub fn set_path(&self, path: String) {
         let mut guard = self.path.lock().unwrap();
         *guard = Some(path);
-        *self.new_file_loaded.lock().unwrap() = true;
     }
 
     pub fn get_path(&self) -> Option<String> {
         self.path.lock().unwrap().clone()
     }
 
+    /// Asks the audio thread to export the sample to a path
+    pub fn request_export(&self, path: PathBuf) {
+        *self.export_path.lock().unwrap() = Some(path);
+        *self.export_message.lock().unwrap() = None;
+        self.export_requested.store(true, Ordering::Release);
+    }
+
+    /// Returns whether an export was asked for, clearing the request. Safe to call
+    /// from the audio thread
+    pub fn take_export_request(&self) -> bool {
+        self.export_requested.swap(false, Ordering::AcqRel)
+    }
+
+    pub fn get_export_path(&self) -> Option<PathBuf> {
+        self.export_path.lock().unwrap().clone()
+    }
+
+    pub fn set_export_message(&self, message: String) {
+        *self.export_message.lock().unwrap() = Some(message);
+    }
+
+    pub fn get_export_message(&self) -> Option<String> {
+        self.export_message.lock().unwrap().clone()
+    }
+
     pub fn clear(&self) {
         let mut guard = self.path.lock().unwrap();
         *guard = None;
     }
+}
+
+/// The keyswitch articulations of the loaded instrument, shared with the editor
+pub struct Articulations {
+    /// Note of the active keyswitch, or -1 if there is none
+    active: AtomicI32,
+    /// Keyswitch notes and the names of the articulations they select
+    names: Mutex<Vec<(u8, String)>>,
+}
+
+impl Default for Articulations {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Articulations {
+    pub fn new() -> Self {
+        Self {
+            active: AtomicI32::new(-1),
+            names: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Stores the articulations of a newly loaded instrument. Called off the audio thread
+    pub fn set_names(&self, loaded: &LoadedInstrument) {
+        let names = match loaded {
+            LoadedInstrument::Sfz(instrument) => instrument.articulations(),
+            _ => Vec::new(),
+        };
+        *self.names.lock().unwrap() = names;
+    }
 
-    pub fn is_new_file_loaded(&self) -> bool {
-        *self.new_file_loaded.lock().unwrap()
+    /// Stores the active keyswitch. Safe to call from the audio thread
+    pub fn set_active(&self, keyswitch: Option<u8>) {
+        self.active.store(keyswitch.map_or(-1, |note| note as i32), Ordering::Relaxed);
     }
 
-    pub fn clear_new_file_flag(&self) {
-        *self.new_file_loaded.lock().unwrap() = false;
+    /// Returns the name of the active articulation, if the instrument has any
+    pub fn active_name(&self) -> Option<String> {
+        let active = self.active.load(Ordering::Relaxed);
+        self.names.lock().unwrap().iter()
+            .find(|(note, _)| *note as i32 == active)
+            .map(|(_, name)| name.clone())
     }
 }
 
@@ -0,0 +1,142 @@
+use crate::sample_buffer::SampleBuffer;
+
+// The range of fundamentals that are looked for, A0 to C8
+const MIN_FREQUENCY: f32 = 27.5;
+const MAX_FREQUENCY: f32 = 4186.0;
+/// How far the normalised difference has to dip for a lag to be taken as the period
+const THRESHOLD: f32 = 0.15;
+/// Windows quieter than this RMS aren't analysed
+const SILENCE: f32 = 0.001;
+/// The number of windows spread across the sample whose pitches are compared
+const NUM_WINDOWS: usize = 5;
+
+/// Estimates the fundamental of a mono signal in Hz with the YIN algorithm.
+///
+/// Several windows spread across the signal are analysed, skipping the very start
+/// so the attack doesn't throw it off, and the median of their pitches is returned.
+/// Returns `None` for silence, noise and signals shorter than two periods of the
+/// lowest note
+pub fn detect_pitch(samples: &[f32], sample_rate: f32)->Option<f32>{
+    let min_lag = ((sample_rate / MAX_FREQUENCY) as usize).max(2);
+    let max_lag = ((sample_rate / MIN_FREQUENCY).ceil() as usize).min(samples.len() / 2);
+    if max_lag <= min_lag + 1{
+        return None
+    }
+    // Each window is compared against itself shifted by up to `max_lag`
+    let window = max_lag;
+    let spare = samples.len() - window - max_lag;
+    let mut pitches: Vec<f32> = (1..=NUM_WINDOWS)
+        .map(|i| &samples[spare * i / (NUM_WINDOWS + 1)..][..window + max_lag])
+        .filter(|frame| rms(frame) >= SILENCE)
+        .filter_map(|frame| yin(frame, window, min_lag, max_lag, sample_rate))
+        .collect();
+    if pitches.is_empty(){
+        return None
+    }
+    pitches.sort_by(f32::total_cmp);
+    Some(pitches[pitches.len() / 2])
+}
+
+/// Estimates the root note of a sample, and the tuning in cents that brings it in
+/// tune with that note
+pub fn detect_root(buffer: &SampleBuffer, sample_rate: f32)->Option<(u8, f32)>{
+    let num_channels = buffer.num_channels();
+    if num_channels == 0{
+        return None
+    }
+    let mono: Vec<f32> = (0..buffer.capacity())
+        .map(|i| (0..num_channels).map(|channel| buffer.channel(channel).get(i)).sum::<f32>() / num_channels as f32)
+        .collect();
+    let frequency = detect_pitch(&mono, sample_rate)?;
+    let note = 69.0 + 12.0 * (frequency / 440.0).log2();
+    let root = note.round();
+    if !(0.0..=127.0).contains(&root){
+        return None
+    }
+    Some((root as u8, (root - note) * 100.0))
+}
+
+fn rms(frame: &[f32])->f32{
+    (frame.iter().map(|sample| sample * sample).sum::<f32>() / frame.len() as f32).sqrt()
+}
+
+/// Finds the period of one window, returning it as a frequency
+fn yin(frame: &[f32], window: usize, min_lag: usize, max_lag: usize, sample_rate: f32)->Option<f32>{
+    // Cumulative mean normalised difference: how unlike itself the window is at each
+    // lag, relative to the lags before it
+    let mut difference = vec![1.0f32; max_lag + 1];
+    let mut running_sum = 0.0;
+    for lag in 1..=max_lag{
+        let sum: f32 = frame[..window].iter().zip(frame[lag..].iter())
+            .map(|(a, b)| (a - b) * (a - b))
+            .sum();
+        running_sum += sum;
+        if running_sum > 0.0{
+            difference[lag] = sum * lag as f32 / running_sum;
+        }
+    }
+    // The first dip below the threshold is the fundamental, the ones at its multiples
+    // are only as deep
+    let mut lag = (min_lag..max_lag).find(|lag| difference[*lag] < THRESHOLD)?;
+    while lag + 1 < max_lag && difference[lag + 1] < difference[lag]{
+        lag += 1;
+    }
+    // Fit a parabola through the minimum and its neighbours for a fractional period
+    let (before, at, after) = (difference[lag - 1], difference[lag], difference[lag + 1]);
+    let curvature = before - 2.0 * at + after;
+    let shift = if curvature > f32::EPSILON { 0.5 * (before - after) / curvature } else { 0.0 };
+    Some(sample_rate / (lag as f32 + shift))
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+    use std::f32::consts::TAU;
+
+    /// One second of a tone with the given harmonic amplitudes, the first being the
+    /// fundamental's
+    fn tone(frequency: f32, harmonics: &[f32], sample_rate: f32)->Vec<f32>{
+        (0..sample_rate as usize)
+            .map(|i| harmonics.iter().enumerate()
+                .map(|(h, amplitude)| amplitude * (TAU * frequency * (h + 1) as f32 * i as f32 / sample_rate).sin())
+                .sum::<f32>() * 0.3)
+            .collect()
+    }
+    fn root_of(samples: &[f32], sample_rate: f32)->Option<(u8, f32)>{
+        detect_root(&SampleBuffer::from_interleaved(samples, 1), sample_rate)
+    }
+
+    #[test]
+    fn test_sines(){
+        for (note, cents, sample_rate) in [(69, 0.0, 48000.0), (60, 30.0, 44100.0), (33, -20.0, 44100.0), (96, 10.0, 96000.0)]{
+            let frequency = 440.0 * 2.0f32.powf((note as f32 + cents * 0.01 - 69.0) / 12.0);
+            let (root, fine_tune) = root_of(&tone(frequency, &[1.0], sample_rate), sample_rate).unwrap();
+            assert_eq!(root, note, "{} Hz", frequency);
+            // The tuning undoes how far off the note the sample is
+            assert!((fine_tune + cents).abs() < 2.0, "{} Hz tuned by {} cents", frequency, fine_tune);
+        }
+    }
+    #[test]
+    fn test_harmonic_tones(){
+        // The second and third harmonics are louder than the fundamental
+        let (root, fine_tune) = root_of(&tone(110.0, &[0.4, 1.0, 0.7, 0.5, 0.3], 48000.0), 48000.0).unwrap();
+        assert_eq!(root, 45);
+        assert!(fine_tune.abs() < 2.0);
+        // A sawtooth-like tone, in stereo with the channels out of phase
+        let saw = tone(311.13, &[1.0, 0.5, 0.33, 0.25, 0.2, 0.17, 0.14, 0.12], 44100.0);
+        let stereo: Vec<f32> = saw.iter().flat_map(|sample| [*sample, -0.5 * sample]).collect();
+        let (root, _) = detect_root(&SampleBuffer::from_interleaved(&stereo, 2), 44100.0).unwrap();
+        assert_eq!(root, 63);
+    }
+    #[test]
+    fn test_unpitched(){
+        assert_eq!(root_of(&vec![0.0; 48000], 48000.0), None);
+        assert_eq!(root_of(&tone(440.0, &[1.0], 48000.0)[..100], 48000.0), None);
+        let mut seed = 1u32;
+        let noise: Vec<f32> = (0..48000).map(|_| {
+            seed = seed.wrapping_mul(1664525).wrapping_add(1013904223);
+            (seed >> 8) as f32 / (1 << 23) as f32 - 1.0
+        }).collect();
+        assert_eq!(root_of(&noise, 48000.0), None);
+    }
+}
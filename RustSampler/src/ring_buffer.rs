@@ -1,3 +1,5 @@
+use nih_plug::params::enums::Enum;
+
 #[derive(Clone)]
 pub struct RingBuffer<T> {
     // TODO: fill this in.
@@ -89,6 +91,10 @@ impl<T: Copy + Default> RingBuffer<T> {
         }
     }
 
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
     pub fn capacity(&self) -> usize {
         // Return the length of the internal buffer.
         //todo!()
@@ -99,6 +105,18 @@ impl<T: Copy + Default> RingBuffer<T> {
         self.buffer.resize(new_size, value);
     }
 }
+/// Interpolation kernels used when reading at a fractional offset
+#[derive(Clone, Copy, PartialEq, Enum, Debug)]
+pub enum Interpolation {
+    Linear,
+    Cubic, // 4-point cubic Hermite
+    Lagrange, // 6-point Lagrange
+    Sinc, // Blackman windowed sinc
+}
+
+/// Number of samples on either side of the read position used by the sinc kernel
+const SINC_HALF_WIDTH: i64 = 8;
+
 impl RingBuffer<f32>{
     // returns a value at a a non-integer offset for fractional delays
     pub fn get_frac(&self, offset: f32)->f32{
@@ -111,6 +129,62 @@ impl RingBuffer<f32>{
         let frac = offset.fract();
         floor_samp * (1.0 - frac) + ceil_samp * frac
     }
+    /// Returns a value at a non-integer offset using the given interpolation kernel
+    pub fn get_frac_with(&self, offset: f32, interpolation: Interpolation)->f32{
+        let idx = offset.floor() as i64;
+        let frac = offset - offset.floor();
+        match interpolation{
+            Interpolation::Linear => {
+                let x0 = self.get_clamped(idx);
+                let x1 = self.get_clamped(idx + 1);
+                x0 + (x1 - x0) * frac
+            },
+            Interpolation::Cubic => {
+                let xm1 = self.get_clamped(idx - 1);
+                let x0 = self.get_clamped(idx);
+                let x1 = self.get_clamped(idx + 1);
+                let x2 = self.get_clamped(idx + 2);
+                let c1 = 0.5 * (x1 - xm1);
+                let c2 = xm1 - 2.5 * x0 + 2.0 * x1 - 0.5 * x2;
+                let c3 = 0.5 * (x2 - xm1) + 1.5 * (x0 - x1);
+                ((c3 * frac + c2) * frac + c1) * frac + x0
+            },
+            Interpolation::Lagrange => {
+                let mut out = 0.0;
+                for k in -2..=3_i64{
+                    let mut weight = 1.0;
+                    for j in -2..=3_i64{
+                        if j != k{
+                            weight *= (frac - j as f32) / (k - j) as f32;
+                        }
+                    }
+                    out += weight * self.get_clamped(idx + k);
+                }
+                out
+            },
+            Interpolation::Sinc => {
+                let mut out = 0.0;
+                let mut weight_sum = 0.0;
+                for k in (1 - SINC_HALF_WIDTH)..=SINC_HALF_WIDTH{
+                    let x = frac - k as f32;
+                    let weight = sinc(x) * blackman(x, SINC_HALF_WIDTH as f32);
+                    out += weight * self.get_clamped(idx + k);
+                    weight_sum += weight;
+                }
+                // Normalising keeps the kernel's DC gain at exactly 1
+                if weight_sum != 0.0 {out / weight_sum} else {out}
+            }
+        }
+    }
+    /// Returns the value at a signed index, holding the first and last samples beyond
+    /// the ends so a sample's tail never bleeds into its attack or the other way round
+    fn get_clamped(&self, idx: i64)->f32{
+        let len = self.capacity() as i64;
+        if len == 0{
+            return 0.0
+        }
+        self.buffer[idx.clamp(0, len - 1) as usize]
+    }
     // meant to be used similarly to pop, simply put in a offset and it will calculate the 
     // read pointer's position based on the write pointer
     pub fn pop_frac(& self, offset: f32)->f32{
@@ -123,13 +197,10 @@ impl RingBuffer<f32>{
             1
         };
         let mut read_int = self.write_ptr as i32 - offset.ceil() as i32 - fract_ptr_offset;
-        let mut read_point;
         if read_int < 0 {
             read_int += self.capacity() as i32;
-            read_point = read_int as usize;
-        }else{
-            read_point = read_int as usize;
         }
+        let read_point = read_int as usize;
         
         let floor_samp = self.get(read_point);
         let ceil_samp = self.get(read_point + 1_usize);
@@ -137,4 +208,59 @@ impl RingBuffer<f32>{
         floor_samp * (1.0 - frac) + ceil_samp * frac
     }
 
-}
\ No newline at end of file
+}
+
+/// Normalised sinc function
+fn sinc(x: f32)->f32{
+    if x == 0.0{
+        1.0
+    }else{
+        let px = std::f32::consts::PI * x;
+        px.sin() / px
+    }
+}
+/// Blackman window centred on 0 spanning -half_width to half_width
+fn blackman(x: f32, half_width: f32)->f32{
+    if x.abs() >= half_width{
+        return 0.0
+    }
+    let phase = std::f32::consts::PI * x / half_width;
+    0.42 + 0.5 * phase.cos() + 0.08 * (2.0 * phase).cos()
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+    macro_rules! assert_close {
+        ($left:expr, $right:expr, $epsilon:expr) => {{
+            let (left, right, epsilon) = ($left, $right, $epsilon);
+            assert!(
+                (left - right).abs() <= epsilon,
+                "{} is not close to {} within an epsilon of {}",
+                left,
+                right,
+                epsilon
+            );
+        }};
+    }
+    #[test]
+    fn test_interpolation(){
+        let mut buffer = RingBuffer::<f32>::new(64);
+        for i in 0..64{
+            buffer.push(i as f32 * 0.01);
+        }
+        let kernels = [Interpolation::Linear, Interpolation::Cubic, Interpolation::Lagrange, Interpolation::Sinc];
+        for kernel in kernels{
+            // Whole offsets return the stored samples
+            assert_close!(buffer.get_frac_with(20.0, kernel), 0.2, 0.0001);
+            // Every kernel follows a straight line away from the wrap point
+            let epsilon = if kernel == Interpolation::Sinc {0.002} else {0.0001};
+            assert_close!(buffer.get_frac_with(30.25, kernel), 0.3025, epsilon);
+            assert_close!(buffer.get_frac_with(30.5, kernel), 0.305, epsilon);
+            // Reads near either end of the ramp don't reach around to the other end
+            assert_close!(buffer.get_frac_with(0.5, kernel), 0.005, 0.01);
+            assert_close!(buffer.get_frac_with(62.5, kernel), 0.625, 0.01);
+            assert_close!(buffer.get_frac_with(63.0, kernel), 0.63, 0.0001);
+        }
+    }
+}
@@ -0,0 +1,207 @@
+use crate::ring_buffer::{RingBuffer, Interpolation};
+
+/// The most octaves a sample is decimated by when building its mip-maps
+const MAX_MIP_LEVELS: usize = 8;
+/// Mip-maps stop once a level would be shorter than this many frames
+const MIN_MIP_LENGTH: usize = 32;
+/// Number of taps of the half-band filter applied before each decimation
+const HALFBAND_TAPS: usize = 31;
+
+/// Holds a deinterleaved audio file, one ring buffer per channel
+#[derive(Clone)]
+pub struct SampleBuffer{
+    channels: Vec<RingBuffer<f32>>,
+    // mip_levels[n][channel] is low-passed and decimated by 2^(n+1)
+    mip_levels: Vec<Vec<RingBuffer<f32>>>,
+}
+
+impl SampleBuffer{
+    /// Creates a new buffer with `num_channels` channels of `length` frames each
+    pub fn new(num_channels: usize, length: usize)->Self{
+        let num_channels = num_channels.max(1);
+        let length = length.max(1);
+        SampleBuffer{
+            channels: vec![RingBuffer::<f32>::new(length); num_channels],
+            mip_levels: Vec::new(),
+        }
+    }
+    /// Builds a buffer by splitting interleaved samples into their channels
+    pub fn from_interleaved(samples: &[f32], num_channels: usize)->Self{
+        let num_channels = num_channels.max(1);
+        let length = samples.len() / num_channels;
+        let mut buffer = SampleBuffer::new(num_channels, length);
+        for frame in samples.chunks_exact(num_channels){
+            for (channel, sample) in buffer.channels.iter_mut().zip(frame.iter()){
+                channel.push(*sample);
+            }
+        }
+        buffer
+    }
+    /// Returns the number of channels in the buffer
+    pub fn num_channels(&self)->usize{
+        self.channels.len()
+    }
+    /// Returns the length of the buffer in frames
+    pub fn capacity(&self)->usize{
+        self.channels[0].capacity()
+    }
+    /// Returns the given channel. The index wraps around the number of channels so
+    /// a mono file feeds every output channel
+    pub fn channel(&self, idx: usize)->&RingBuffer<f32>{
+        &self.channels[idx % self.channels.len()]
+    }
+    /// Returns the given channel mutably, wrapping the index like `channel`
+    pub fn channel_mut(&mut self, idx: usize)->&mut RingBuffer<f32>{
+        let num_channels = self.channels.len();
+        &mut self.channels[idx % num_channels]
+    }
+    /// Returns the sample of the given channel at a fractional frame index
+    pub fn get_frac(&self, channel: usize, offset: f32, interpolation: Interpolation)->f32{
+        self.channel(channel).get_frac_with(offset, interpolation)
+    }
+    /// Returns the sample of the given channel at a fractional frame index when the
+    /// buffer is being read `rate` frames per output sample.
+    /// 
+    /// Rates above 1.0 read from the band-limited mip-maps. Level n is cut off at
+    /// 1/2^n of the original Nyquist, so the levels either side of `log2(rate)` are
+    /// crossfaded by its fractional part. Only the top of the spectrum, which would
+    /// fold back, fades out as the rate rises, and there is no jump at each octave
+    pub fn get_frac_at_rate(&self, channel: usize, offset: f32, rate: f32, interpolation: Interpolation)->f32{
+        if rate <= 1.0 || self.mip_levels.is_empty(){
+            return self.get_frac(channel, offset, interpolation)
+        }
+        let octaves = rate.log2();
+        let level = octaves.floor() as usize;
+        if level >= self.mip_levels.len(){
+            return self.get_level_frac(self.mip_levels.len(), channel, offset, interpolation)
+        }
+        let lower = self.get_level_frac(level, channel, offset, interpolation);
+        let upper = self.get_level_frac(level + 1, channel, offset, interpolation);
+        lower + (upper - lower) * octaves.fract()
+    }
+    /// Reads from a single mip-map level, level 0 being the original sample
+    fn get_level_frac(&self, level: usize, channel: usize, offset: f32, interpolation: Interpolation)->f32{
+        if level == 0{
+            return self.get_frac(channel, offset, interpolation)
+        }
+        let channels = &self.mip_levels[level - 1];
+        let scaled_offset = offset / (1 << level) as f32;
+        channels[channel % channels.len()].get_frac_with(scaled_offset, interpolation)
+    }
+    /// Builds the octave mip-maps used when the sample is pitched up.
+    /// 
+    /// Each level is half-band filtered and decimated from the one above it.
+    /// Should be called once after loading, never on the audio thread
+    pub fn build_mip_maps(&mut self){
+        let kernel = halfband_kernel();
+        self.mip_levels.clear();
+        while self.mip_levels.len() < MAX_MIP_LEVELS{
+            let previous = self.mip_levels.last().unwrap_or(&self.channels);
+            if previous[0].capacity() / 2 < MIN_MIP_LENGTH{
+                break
+            }
+            let level: Vec<RingBuffer<f32>> = previous.iter()
+                .map(|channel| decimate(channel, &kernel))
+                .collect();
+            self.mip_levels.push(level);
+        }
+    }
+    /// Returns the number of mip-map levels below the original sample
+    pub fn num_mip_levels(&self)->usize{
+        self.mip_levels.len()
+    }
+}
+
+/// Creates a Blackman windowed sinc low-pass with its cutoff at half of Nyquist
+fn halfband_kernel()->Vec<f32>{
+    let centre = (HALFBAND_TAPS / 2) as f32;
+    let mut kernel: Vec<f32> = (0..HALFBAND_TAPS).map(|n| {
+        let x = n as f32 - centre;
+        let sinc = if x == 0.0 {1.0} else {(std::f32::consts::FRAC_PI_2 * x).sin() / (std::f32::consts::FRAC_PI_2 * x)};
+        let phase = std::f32::consts::PI * x / (centre + 1.0);
+        let window = 0.42 + 0.5 * phase.cos() + 0.08 * (2.0 * phase).cos();
+        sinc * window
+    }).collect();
+    let sum: f32 = kernel.iter().sum();
+    for tap in kernel.iter_mut(){
+        *tap /= sum;
+    }
+    kernel
+}
+/// Filters a channel with the given kernel and keeps every other sample
+fn decimate(channel: &RingBuffer<f32>, kernel: &[f32])->RingBuffer<f32>{
+    let length = channel.capacity();
+    let centre = (kernel.len() / 2) as i64;
+    let mut out = RingBuffer::<f32>::new(length.div_ceil(2));
+    for idx in (0..length).step_by(2){
+        let mut sample = 0.0;
+        for (k, tap) in kernel.iter().enumerate(){
+            let read = idx as i64 + k as i64 - centre;
+            // Zero padded rather than wrapped so the ends of the sample stay clean
+            if read >= 0 && (read as usize) < length{
+                sample += tap * channel.get(read as usize);
+            }
+        }
+        out.push(sample);
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+    #[test]
+    fn test_deinterleave(){
+        let interleaved = [0.0, 1.0, 0.1, 1.1, 0.2, 1.2];
+        let buffer = SampleBuffer::from_interleaved(&interleaved, 2);
+        assert_eq!(buffer.num_channels(), 2);
+        assert_eq!(buffer.capacity(), 3);
+        for i in 0..3{
+            assert_eq!(buffer.channel(0).get(i), interleaved[i*2]);
+            assert_eq!(buffer.channel(1).get(i), interleaved[i*2+1]);
+        }
+        // Channels wrap around so a third output reads the left channel again
+        assert_eq!(buffer.channel(2).get(1), 0.1);
+    }
+    #[test]
+    fn test_mip_maps(){
+        // Constant signal on the left, Nyquist-rate square on the right
+        let interleaved: Vec<f32> = (0..1024)
+            .flat_map(|i| [1.0, if i % 2 == 0 {1.0} else {-1.0}])
+            .collect();
+        let mut buffer = SampleBuffer::from_interleaved(&interleaved, 2);
+        buffer.build_mip_maps();
+        assert!(buffer.num_mip_levels() > 0);
+        // An octave up, DC passes while content at Nyquist is filtered out
+        let offset = 512.0;
+        assert!((buffer.get_frac_at_rate(0, offset, 2.0, Interpolation::Linear) - 1.0).abs() < 0.01);
+        assert!(buffer.get_frac_at_rate(1, offset, 2.0, Interpolation::Linear).abs() < 0.01);
+        // At the original rate nothing is filtered
+        assert_eq!(buffer.get_frac_at_rate(1, offset, 1.0, Interpolation::Linear), 1.0);
+
+        // The level of a tone read at whole frames, with the mip-maps picked for `rate`
+        let rms_at_rate = |frequency: f32, rate: f32|{
+            let tone: Vec<f32> = (0..4096).map(|i| (std::f32::consts::TAU * frequency * i as f32).sin()).collect();
+            let mut buffer = SampleBuffer::from_interleaved(&tone, 1);
+            buffer.build_mip_maps();
+            let sum: f32 = (0..1000)
+                .map(|i| buffer.get_frac_at_rate(0, 500.0 + i as f32, rate, Interpolation::Linear).powi(2))
+                .sum();
+            (sum / 1000.0).sqrt()
+        };
+        // Just under the original Nyquist only fades out as the rate nears an octave,
+        // so a semitone up sounds almost the same as the original
+        let full = rms_at_rate(0.45, 1.0);
+        assert!((rms_at_rate(0.45, 1.06) - full).abs() < 0.1 * full);
+        let mut previous = full;
+        for rate in [1.2, 1.5, 1.8, 1.99]{
+            let rms = rms_at_rate(0.45, rate);
+            let kept = 1.0 - rate.log2();
+            assert!((rms - full * kept).abs() < 0.05, "near Nyquist at rate {}", rate);
+            assert!(rms < previous);
+            previous = rms;
+            assert!(rms_at_rate(0.1, rate) > 0.55, "passband at rate {}", rate);
+        }
+        assert!(rms_at_rate(0.45, 2.0) < 0.02);
+    }
+}
@@ -0,0 +1,95 @@
+use crate::sample_buffer::SampleBuffer;
+use crate::sampler_engine::{create_buffer, LoadedSample};
+use crate::decoder::{LoadError, SampleLoop};
+use std::{collections::HashMap, path::{Path, PathBuf}, sync::Arc};
+
+/// A decoded sample shared between every region and voice that plays it
+#[derive(Clone)]
+pub struct PooledSample{
+    pub buffer: Arc<SampleBuffer>,
+    pub sample_rate: f32,
+    /// The loop stored in the sample file, used when a region doesn't set its own
+    pub sustain_loop: Option<SampleLoop>,
+}
+
+/// Decodes each sample file once and hands out reference counted handles to it
+#[derive(Clone, Default)]
+pub struct SamplePool{
+    samples: HashMap<PathBuf, PooledSample>,
+}
+
+impl SamplePool{
+    pub fn new()->Self{
+        SamplePool{
+            samples: HashMap::new(),
+        }
+    }
+    /// Returns the sample at the given path, decoding it the first time it is asked for.
+    /// 
+    /// Reads from disk, so this must never be called on the audio thread
+    pub fn get_or_load(&mut self, path: &Path)->Result<PooledSample, LoadError>{
+        if let Some(sample) = self.samples.get(path){
+            return Ok(sample.clone())
+        }
+        let LoadedSample{ buffer, sample_rate, metadata } = create_buffer(&path.to_string_lossy())?;
+        Ok(self.insert(path, buffer, sample_rate, metadata.sustain_loop))
+    }
+    /// Adds an already decoded sample under the given key, building its mip-maps.
+    /// Used for samples embedded in another file, like a SoundFont
+    pub fn insert(&mut self, key: &Path, mut buffer: SampleBuffer, sample_rate: f32, sustain_loop: Option<SampleLoop>)->PooledSample{
+        buffer.build_mip_maps();
+        let sample = PooledSample{
+            buffer: Arc::new(buffer),
+            sample_rate,
+            sustain_loop,
+        };
+        self.samples.insert(key.to_path_buf(), sample.clone());
+        sample
+    }
+    /// Returns the sample stored under the given key, if it has been loaded
+    pub fn get(&self, key: &Path)->Option<PooledSample>{
+        self.samples.get(key).cloned()
+    }
+    /// Returns the number of decoded samples in the pool
+    pub fn len(&self)->usize{
+        self.samples.len()
+    }
+    /// Returns whether nothing has been decoded yet
+    pub fn is_empty(&self)->bool{
+        self.samples.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+    use crate::sampler_engine::{SamplerEngine, LoadedInstrument, EngineEvent};
+    use crate::sfz::SfzInstrument;
+    use std::fs;
+    #[test]
+    fn test_shared_samples(){
+        let dir = std::env::temp_dir().join("rust_sampler_pool_test");
+        fs::create_dir_all(&dir).unwrap();
+        let spec = hound::WavSpec{ channels: 1, sample_rate: 44100, bits_per_sample: 16, sample_format: hound::SampleFormat::Int };
+        let mut writer = hound::WavWriter::create(dir.join("shared.wav"), spec).unwrap();
+        for _ in 0..1000{
+            writer.write_sample(16384i16).unwrap();
+        }
+        writer.finalize().unwrap();
+        fs::write(dir.join("test.sfz"), "<region> sample=shared.wav key=60\n<region> sample=shared.wav key=62").unwrap();
+        let (instrument, _) = SfzInstrument::from_file(&dir.join("test.sfz")).unwrap();
+        // Both regions play the one decoded copy
+        assert_eq!(instrument.pool.len(), 1);
+        assert!(Arc::ptr_eq(&instrument.regions[0].sample.buffer, &instrument.regions[1].sample.buffer));
+        // Everything was decoded up front, so notes still play once the file is gone
+        fs::remove_file(dir.join("shared.wav")).unwrap();
+        let mut engine = SamplerEngine::new(44100.0, 1);
+        engine.swap_instrument(LoadedInstrument::Sfz(instrument));
+        let mut output = vec![0.0; 64];
+        engine.render(&mut [&mut output[..]], &[
+            EngineEvent::NoteOn { timing: 0, channel: 0, note: 60, velocity: 1.0 },
+            EngineEvent::NoteOn { timing: 0, channel: 0, note: 62, velocity: 1.0 },
+        ]);
+        assert!(output.iter().all(|sample| *sample > 0.0));
+    }
+}
@@ -1,23 +1,54 @@
-use crate::{sampler_voice,ring_buffer,adsr};
-use sampler_voice::{SamplerVoice,SustainModes,VoiceType};
-use ring_buffer::RingBuffer;
-use std::{collections::HashMap, path::Path};
-use hound::SampleFormat;
+use crate::{sampler_voice,sample_buffer,adsr,decoder,pitch};
+use crate::ring_buffer::Interpolation;
+use crate::sfz::{SfzInstrument,SfzRegion,LoopMode,SfzWarning,SfzError};
+use crate::sf2::{load_sf2,Sf2Error,PresetNumber};
+use crate::dspreset::{load_dspreset,load_dslibrary,DsError};
+use crate::decoder::{LoadError,SampleMetadata,SampleLoop};
+use sampler_voice::{SamplerVoice,SustainModes,VoiceType,OffMode};
+use sample_buffer::SampleBuffer;
+use std::{collections::HashMap, fmt, path::Path, sync::Arc};
 use adsr::AdsrState;
-use sofiza::{Instrument, Opcode};
+
+/// The SFZ format's default amp envelope as (attack, decay, sustain, release), used
+/// before any warp parameters have been set
+const SFZ_ENVELOPE: (f32, f32, f32, f32) = (0.0, 0.0, 1.0, 0.001);
+
+/// A decoded file, ready to be swapped into the engine from the audio thread
+pub enum LoadedInstrument{
+    Warp{buffer: SampleBuffer, sample_rate: f32, metadata: SampleMetadata},
+    Sfz(SfzInstrument),
+}
 
 #[derive(Clone)]
 pub struct SamplerEngine{
     num_voices: u8,
-    sound_bank: HashMap<u8,(String,f32,RingBuffer<f32>, SamplerVoice)>,
+    sound_bank: HashMap<u8,(String,f32,SampleBuffer, SamplerVoice)>,
     file_names: Vec<String>,
-    warp_buffer: RingBuffer<f32>,
+    warp_buffer: SampleBuffer,
     sampler_mode: SamplerMode,
     warp_voices: Vec<SamplerVoice>,
     sample_rate: f32,
     num_channels: usize,
     warp_sr_scalar: f32,
-    instrument: Instrument,
+    /// Root note, tuning, gain and loop stored in the warp sample's file
+    warp_metadata: SampleMetadata,
+    /// Whether the warp voices loop where the file says rather than at the sustain
+    /// parameters. Cleared as soon as one of those parameters changes
+    use_file_loop: bool,
+    /// The root note chosen by hand, in place of the file's or the detected one
+    root_override: Option<u8>,
+    instrument: SfzInstrument,
+    warp_params: Option<WarpParams>,
+    // Shared by voices that have nothing to play, so voices never free sample memory
+    empty_buffer: Arc<SampleBuffer>,
+    // New voices are cloned from this when the number of voices grows
+    voice_template: SamplerVoice,
+    // State of the random number generator used for SFZ lorand/hirand
+    random_state: u64,
+    // Number of frames rendered so far, used to time how long notes are held
+    frame_clock: u64,
+    // The value (0-1) of every CC on each of the 16 MIDI channels
+    cc_state: [[f32; 128]; 16],
 }
 #[derive(PartialEq,Clone)]
 pub enum SamplerMode{
@@ -25,13 +56,79 @@ pub enum SamplerMode{
     Assign, // For when you load multiple samples and assign them to midi notes
     Sfz, // For when you load an sfz file
 }
+/// Note events that are handed to the engine together with a block of audio
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum EngineEvent{
+    NoteOn{timing: u32, channel: u8, note: u8, velocity: f32},
+    NoteOff{timing: u32, channel: u8, note: u8},
+    /// A MIDI CC moving to `value` (0-1)
+    ControlChange{timing: u32, channel: u8, cc: u8, value: f32},
+}
+impl EngineEvent{
+    /// Returns the sample offset of the event within its block
+    pub fn timing(&self)->u32{
+        match *self{
+            EngineEvent::NoteOn { timing, .. } => timing,
+            EngineEvent::NoteOff { timing, .. } => timing,
+            EngineEvent::ControlChange { timing, .. } => timing,
+        }
+    }
+}
+/// Playback parameters shared by all of the warp sampler's voices
+/// 
+/// Times are in seconds, points are in percent of the sample length
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct WarpParams{
+    pub num_voices: u8,
+    pub attack: f32,
+    pub decay: f32,
+    pub sustain: f32,
+    pub release: f32,
+    pub start_point: f32,
+    pub end_point: f32,
+    pub sus_start: f32,
+    pub sus_end: f32,
+    pub sus_mode: SustainModes,
+    pub fade_time: f32,
+    pub interpolation: Interpolation,
+}
+
+/// Where the warp sampler is playing its sample, in frames, so it can be written
+/// back into the file
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct WarpExport{
+    pub root_note: u8,
+    /// Fine tuning in cents applied on playback
+    pub fine_tune: f32,
+    pub start: u32,
+    pub end: u32,
+    pub sustain_loop: Option<SampleLoop>,
+}
+
+impl WarpExport{
+    /// The metadata to embed, marking the start and end of the sample with cues
+    pub fn metadata(&self)->SampleMetadata{
+        SampleMetadata{
+            root_note: Some(self.root_note),
+            fine_tune: self.fine_tune,
+            sustain_loop: self.sustain_loop,
+            cues: vec![self.start, self.end],
+            ..SampleMetadata::default()
+        }
+    }
+}
 
 impl SamplerEngine{
     pub fn new(sample_rate_: f32, num_channels_: usize) -> Self{
         
         let files = vec!["".to_string();100];
-        let buff = RingBuffer::<f32>::new(1);
-        let voices_ = vec![SamplerVoice::new(num_channels_,sample_rate_,64,VoiceType::Warp);6];
+        let buff = SampleBuffer::new(num_channels_, 1);
+        let empty_buffer = Arc::new(SampleBuffer::new(num_channels_, 1));
+        let mut template = SamplerVoice::new(num_channels_,sample_rate_,64,VoiceType::Warp);
+        template.internal_buffer = empty_buffer.clone();
+        // Room for the max number of voices, so changing it doesn't allocate
+        let mut voices_ = Vec::with_capacity(24);
+        voices_.resize(6, template.clone());
 
         let mut engine = SamplerEngine{
             num_voices: 6,
@@ -43,58 +140,230 @@ impl SamplerEngine{
             sample_rate: sample_rate_,
             num_channels: num_channels_,
             warp_sr_scalar: sample_rate_,
-            instrument: Instrument::new(),
+            warp_metadata: SampleMetadata::default(),
+            use_file_loop: false,
+            root_override: None,
+            instrument: SfzInstrument::default(),
+            warp_params: None,
+            empty_buffer,
+            voice_template: template,
+            random_state: 0,
+            frame_clock: 0,
+            cc_state: [[0.0; 128]; 16],
         };
         engine.file_names.clear();
+        engine.set_random_seed(0);
         engine
     }
-    pub fn process(&mut self)->f32{
-        let mut out_samp = 0.0;
+    /// Renders a block of audio into `outputs`, one slice per output channel.
+    /// 
+    /// Events are expected to be sorted by their timing and are applied at their
+    /// sample offset within the block
+    pub fn render(&mut self, outputs: &mut [&mut [f32]], events: &[EngineEvent]){
+        let num_frames = outputs.first().map_or(0, |output| output.len());
+        self.render_range(outputs, events, 0, num_frames);
+    }
+    /// Renders frames `start..end` of a block, leaving the rest of `outputs` alone, so
+    /// a block can be rendered in pieces with the parameters changing in between.
+    /// 
+    /// Events are applied at their timing, clamped to the range
+    pub fn render_range(&mut self, outputs: &mut [&mut [f32]], events: &[EngineEvent], start: usize, end: usize){
+        for output in outputs.iter_mut(){
+            output[start..end].fill(0.0);
+        }
+        let mut block_start = start;
+        for event in events.iter(){
+            let timing = (event.timing() as usize).clamp(start, end);
+            if timing > block_start{
+                self.render_voices(outputs, block_start, timing);
+                block_start = timing;
+            }
+            self.handle_event(event);
+        }
+        self.render_voices(outputs, block_start, end);
+    }
+    /// Applies a single note or CC event
+    fn handle_event(&mut self, event: &EngineEvent){
+        match *event{
+            EngineEvent::NoteOn { channel, note, velocity, .. } => self.note_on(channel, note, velocity),
+            EngineEvent::NoteOff { channel, note, .. } => self.note_off(channel, note),
+            EngineEvent::ControlChange { channel, cc, value, .. } => self.control_change(channel, cc, value),
+        }
+    }
+    /// Renders frames `start..end` of every voice into `outputs`
+    fn render_voices(&mut self, outputs: &mut [&mut [f32]], start: usize, end: usize){
+        self.frame_clock += end.saturating_sub(start) as u64;
         match self.sampler_mode{
             SamplerMode::Warp =>{
                 for voice in self.warp_voices.iter_mut(){
-                    out_samp += voice.process(&mut self.warp_buffer, 
-                                                self.warp_sr_scalar);
+                    voice.render(&self.warp_buffer, self.warp_sr_scalar, outputs, start, end);
                 }
             },
             SamplerMode::Assign =>{
                 for (_note, (_name,sr_scalar,buff,voice)) in self.sound_bank.iter_mut(){
-                    out_samp += voice.process(buff,*sr_scalar);
+                    voice.render(buff,*sr_scalar,outputs, start, end);
                 }
             },
             SamplerMode::Sfz =>{
                 for voice in self.warp_voices.iter_mut(){
-                    out_samp += voice.process_sfz(self.warp_sr_scalar);
+                    voice.render_sfz(outputs, start, end);
+                }
+            }
+        }
+    }
+    /// Swaps a loaded file into the engine and switches to the matching mode. 
+    /// 
+    /// Doesn't allocate, so it's safe to call on the audio thread. Returns the contents
+    /// that were replaced so they can be freed somewhere else
+    pub fn swap_instrument(&mut self, loaded: LoadedInstrument)->LoadedInstrument{
+        // Let go of the old samples while the old contents still hold them, so
+        // the voices never drop the last reference
+        for voice in self.warp_voices.iter_mut(){
+            voice.adsr.reset();
+            voice.set_internal_buffer(self.empty_buffer.clone(), 1.0);
+            voice.clear_region_settings();
+        }
+        match loaded{
+            LoadedInstrument::Warp { buffer, sample_rate, metadata } => {
+                let old_sample_rate = self.warp_sr_scalar * self.sample_rate;
+                let old_buffer = std::mem::replace(&mut self.warp_buffer, buffer);
+                self.warp_sr_scalar = sample_rate / self.sample_rate;
+                self.set_mode(SamplerMode::Warp);
+                let old_metadata = self.set_warp_metadata(metadata);
+                LoadedInstrument::Warp { buffer: old_buffer, sample_rate: old_sample_rate, metadata: old_metadata }
+            },
+            LoadedInstrument::Sfz(instrument) => {
+                let old_instrument = std::mem::replace(&mut self.instrument, instrument);
+                self.set_mode(SamplerMode::Sfz);
+                LoadedInstrument::Sfz(old_instrument)
+            }
+        }
+    }
+    /// Sets the parameters of the warp sampler's voices. 
+    /// 
+    /// The voices are only updated if the parameters differ from the last call
+    pub fn set_warp_params(&mut self, params: WarpParams){
+        if self.warp_params != Some(params){
+            // Moving any of the sustain controls hands the loop back to them
+            if let Some(old) = self.warp_params{
+                if (old.sus_start, old.sus_end, old.sus_mode) != (params.sus_start, params.sus_end, params.sus_mode){
+                    self.use_file_loop = false;
                 }
             }
+            self.warp_params = Some(params);
+            self.refresh_warp_params();
+        }
+    }
+    /// Pushes the last set warp parameters to all of the warp voices
+    fn refresh_warp_params(&mut self){
+        if let Some(params) = self.warp_params{
+            self.set_num_voices(params.num_voices);
+            self.set_adsr_warp(params.attack, params.decay, params.sustain, params.release);
+            self.set_points_warp(params.start_point, params.end_point);
+            match self.warp_metadata.sustain_loop.filter(|_| self.use_file_loop){
+                Some(sample_loop) => self.set_file_loop_warp(sample_loop),
+                None => {
+                    self.set_sus_looping_warp(params.sus_mode);
+                    self.set_sus_points_warp(params.sus_start, params.sus_end);
+                },
+            }
+            self.set_fade_time_warp(params.fade_time);
+            self.set_interpolation_warp(params.interpolation);
+        }
+    }
+    /// Takes on the metadata of a new warp sample, returning the previous sample's.
+    /// 
+    /// The warp voices are based on the sample's root note, scaled by its gain, and
+    /// loop where its file says until the sustain parameters are changed
+    fn set_warp_metadata(&mut self, metadata: SampleMetadata)->SampleMetadata{
+        let old_metadata = std::mem::replace(&mut self.warp_metadata, metadata);
+        self.use_file_loop = self.warp_metadata.sustain_loop.is_some();
+        let gain = 10.0f32.powf(self.warp_metadata.gain / 20.0);
+        for voice in self.warp_voices.iter_mut().chain(std::iter::once(&mut self.voice_template)){
+            voice.set_amplitude(gain);
+        }
+        self.apply_warp_root();
+        self.refresh_warp_params();
+        old_metadata
+    }
+    /// Sets the root note of the warp sample by hand, or goes back to the one from its
+    /// file or pitch detection with `None`. Doesn't allocate
+    pub fn set_root_override(&mut self, root_note: Option<u8>){
+        if self.root_override != root_note{
+            self.root_override = root_note;
+            self.apply_warp_root();
+        }
+    }
+    /// Returns the root note and the tuning in cents the warp sample is played with:
+    /// the override if there is one, otherwise the sample's own, otherwise 60
+    fn warp_root(&self)->(u8, f32){
+        match (self.root_override, self.warp_metadata.root_note){
+            (Some(root_note), _) => (root_note, 0.0),
+            (None, Some(root_note)) => (root_note, self.warp_metadata.fine_tune),
+            (None, None) => (60, 0.0),
+        }
+    }
+    /// Bases and tunes the warp voices on the warp sample's root note. Outside of Warp
+    /// mode the voices are playing regions or assigned samples with their own pitch, so
+    /// only the template is changed and the voices pick it up when Warp mode comes back
+    fn apply_warp_root(&mut self){
+        let (root_note, fine_tune) = self.warp_root();
+        self.voice_template.set_base_midi(root_note);
+        self.voice_template.set_tuning(fine_tune * 0.01, 1.0);
+        if self.sampler_mode == SamplerMode::Warp{
+            for voice in self.warp_voices.iter_mut(){
+                voice.set_base_midi(root_note);
+                voice.set_tuning(fine_tune * 0.01, 1.0);
+            }
         }
-        out_samp
+    }
+    /// Sets the warp voices' sustain loop to a loop from the sample's file
+    fn set_file_loop_warp(&mut self, sample_loop: SampleLoop){
+        if self.sampler_mode == SamplerMode::Warp{
+            for voice in self.warp_voices.iter_mut(){
+                voice.set_sus_loop_mode(sample_loop.mode);
+                voice.set_loop_range(sample_loop.start as f32, sample_loop.end as f32 + 1.0);
+            }
+        }
+    }
+    /// Decodes a file into the warp buffer, taking on its metadata.
+    /// The previous sample is kept if the file can't be loaded
+    fn load_warp_file(&mut self, file_path: &str)->Result<(), LoadError>{
+        let mut sample = create_buffer(file_path)?;
+        sample.detect_missing_root();
+        let LoadedSample{ mut buffer, sample_rate, metadata } = sample;
+        buffer.build_mip_maps();
+        self.warp_buffer = buffer;
+        self.warp_sr_scalar = sample_rate / self.sample_rate;
+        self.set_warp_metadata(metadata);
+        Ok(())
     }
     ///Add a file to the paths of files saved in the file names
     /// and load file into the warp buffer.
-    pub fn add_to_paths_and_load(&mut self, file_path: &str){
-        if file_path.ends_with(".wav"){
-            self.warp_sr_scalar =  fill_warp_buffer(&mut self.warp_buffer, file_path)/
-                                self.sample_rate;
-            self.file_names.push(file_path.to_string());
-        }
+    /// 
+    /// The previous sample is kept if the file can't be loaded
+    pub fn add_to_paths_and_load(&mut self, file_path: &str)->Result<(), LoadError>{
+        self.load_warp_file(file_path)?;
+        self.file_names.push(file_path.to_string());
+        Ok(())
     }
     ///Add a file to the paths of files saved in the file names.
     pub fn add_file_to_paths(&mut self, file_path: &str){
-        if file_path.ends_with(".wav"){
+        if decoder::is_audio_file(Path::new(file_path)){
             self.file_names.push(file_path.to_string());
         }
     }
     ///Load a file into the warp buffer from the list of filepaths that have been added
     /// 
     /// idx will wrap around the size of the file_paths buffer
-    pub fn load_file_by_index(&mut self, idx: usize){
-        if self.file_names.len() > 0{
+    pub fn load_file_by_index(&mut self, idx: usize)->Result<(), LoadError>{
+        if !self.file_names.is_empty(){
             let new_idx = idx % self.file_names.len();
-            if let Some(file_path) = self.file_names.get(new_idx){
-                self.warp_sr_scalar = fill_warp_buffer(&mut self.warp_buffer, &file_path)/self.sample_rate;
+            if let Some(file_path) = self.file_names.get(new_idx).cloned(){
+                self.load_warp_file(&file_path)?;
             }
         }
+        Ok(())
     }
 
     pub fn get_file_name_by_index(&mut self, idx: usize)->Option<String>{
@@ -103,41 +372,67 @@ impl SamplerEngine{
     }
     ///Load file from path into the warp buffer without loading 
     /// into the file names.
-    pub fn load_file_from_path(&mut self, file_path: &str){
-        self.warp_sr_scalar =  fill_warp_buffer(&mut self.warp_buffer, file_path)/
-                                self.sample_rate;
+    pub fn load_file_from_path(&mut self, file_path: &str)->Result<(), LoadError>{
+        self.load_warp_file(file_path)
+    }
+    /// Replaces the warp buffer with an already decoded sample
+    /// 
+    /// Mip-maps are built if the buffer doesn't already have them
+    pub fn set_warp_buffer(&mut self, mut buffer: SampleBuffer, file_sample_rate: f32){
+        if buffer.num_mip_levels() == 0{
+            buffer.build_mip_maps();
+        }
+        self.warp_buffer = buffer;
+        self.warp_sr_scalar = file_sample_rate / self.sample_rate;
+        self.refresh_warp_params();
     }
     /// Assigns an audio file to a midi note for the sound bank. (Assign mode)
     /// 
     /// Will add file to paths if not already there
-    pub fn assign_file_to_midi(&mut self, file_path: &str, note: u8){
+    pub fn assign_file_to_midi(&mut self, file_path: &str, note: u8)->Result<(), LoadError>{
+        let mut sample = create_buffer(file_path)?;
+        sample.detect_missing_root();
+        let LoadedSample{ mut buffer, sample_rate, metadata } = sample;
         if !self.file_names.contains(&file_path.to_string()){
             self.add_file_to_paths(file_path);
         }
-        let (buff,sr) = create_buffer(file_path);
-        let sr_scalar = sr / self.sample_rate;
-        self.sound_bank.insert(note,(file_path.to_string(),sr_scalar,buff,
-                            SamplerVoice::new(self.num_channels,self.sample_rate,note,VoiceType::Assign))); 
+        buffer.build_mip_maps();
+        let sr_scalar = sample_rate / self.sample_rate;
+        // Assigned samples keep their own pitch, only being tuned to the nearest note
+        let mut voice = SamplerVoice::new(self.num_channels,self.sample_rate,note,VoiceType::Assign);
+        if metadata.root_note.is_some(){
+            voice.set_tuning(metadata.fine_tune * 0.01, 1.0);
+        }
+        self.sound_bank.insert(note,(file_path.to_string(),sr_scalar,buffer,voice)); 
+        Ok(())
     }
 
-    /// Load an SFZ file and create an instrument
-    pub fn load_sfz(&mut self, file_path: &str){
-        let result = Instrument::from_file(Path::new(file_path));
-        match result {
-            Ok(instrument) => self.instrument = instrument,
-            Err(_e) => {}
-        }
+    /// Load an SFZ file and create an instrument, decoding all of its samples. 
+    /// 
+    /// Returns what had to be skipped while loading. The current instrument is kept
+    /// if the file couldn't be loaded at all
+    pub fn load_sfz(&mut self, file_path: &str)->Result<Vec<SfzWarning>, SfzError>{
+        let (instrument, warnings) = SfzInstrument::from_file(Path::new(file_path))?;
+        self.swap_instrument(LoadedInstrument::Sfz(instrument));
+        Ok(warnings)
     }
 
     /// Triggers a "note on" message and allocates a voice, 
-    ///  stealing if necessary
-    pub fn note_on(&mut self, note: u8, velocity: f32){
+    ///  stealing if necessary. The channel selects which CC values SFZ regions are
+    ///  filtered by
+    pub fn note_on(&mut self, channel: u8, note: u8, velocity: f32){
         match self.sampler_mode {
             SamplerMode::Warp =>{
                 let voice_id = self.get_voice_id();
                 self.warp_voices[voice_id].note_on(note, velocity);
             },
             SamplerMode::Assign =>{
+                let choke_group = self.sound_bank.values()
+                    .find(|(_name,_sr_scalar,_buff,voice)| voice.base_midi == note)
+                    .map(|(_name,_sr_scalar,_buff,voice)| voice.choke_group());
+                if let Some(choke_group) = choke_group{
+                    self.choke_voices(choke_group);
+                }
                 for (_note_, (_name,_sr_scalar,_buff,voice)) in self.sound_bank.iter_mut(){
                     if voice.base_midi == note{
                         voice.note_on(note, velocity);
@@ -146,96 +441,132 @@ impl SamplerEngine{
                 } 
             },
             SamplerMode::Sfz =>{
-                let instrument = self.instrument.clone();
-                for region in instrument.regions.iter(){
-                    let mut lokey = u8::MIN;
-                    let mut hikey = u8::MAX;
-                    let mut lovel = f32::MIN;
-                    let mut hivel = f32::MAX;
-                    match region.opcodes.get("lokey") {
-                        Some(value) => {
-                            match value {
-                                Opcode::lokey(value) => {
-                                    lokey = *value;
-                                },
-                                _ => println!("Something else")
-                            }
-                        },
-                        None => {}
-                    }
-                    match region.opcodes.get("hikey") {
-                        Some(value) => {
-                            match value {
-                                Opcode::hikey(value) => {
-                                    hikey = *value;
-                                },
-                                _ => println!("Something else")
-                            }
-                        },
-                        None => {}
-                    }
-                    match region.opcodes.get("lovel") {
-                        Some(value) => {
-                            match value {
-                                Opcode::lovel(value) => {
-                                    lovel = *value as f32;
-                                },
-                                _ => println!("Something else")
-                            }
-                        },
-                        None => {}
-                    }
-                    match region.opcodes.get("hivel") {
-                        Some(value) => {
-                            match value {
-                                Opcode::hivel(value) => {
-                                    hivel = *value as f32;
-                                },
-                                _ => println!("Something else")
-                            }
-                        },
-                        None => {}
-                    }
-                    // Conditional filters
-                    if note >= lokey && note <= hikey && velocity*127.0 >= lovel && velocity*127.0 <= hivel {
-                        let voice_id = self.get_voice_id();
-                        match region.opcodes.get("sample") {
-                            Some(value) => {
-                                match value {
-                                    Opcode::sample(value) => {
-                                        match value.to_str() {
-                                            Some(file_path) => {
-                                                let result = create_buffer(file_path);
-                                                self.warp_sr_scalar = result.1/self.sample_rate;
-                                                self.warp_voices[voice_id].internal_buffer = result.0.clone();
-                                            },
-                                            None => { panic!("Could not convert value to string") }
-                                        }
-                                    },
-                                    _ => println!("Something else")
-                                }
-                            },
-                            None => {}
-                        }
-                        match region.opcodes.get("pitch_keycenter") {
-                            Some(value) => {
-                                match value {
-                                    Opcode::pitch_keycenter(value) => {
-                                        self.warp_voices[voice_id].base_midi = *value;
-                                    },
-                                    _ => println!("Something else")
-                                }
-                            },
-                            None => {}
-                        }
-                        self.warp_voices[voice_id].note_on(note, velocity);
-                    }
+                // Keyswitches change the articulation instead of sounding
+                if self.instrument.key_down(note, velocity, self.frame_clock){
+                    return;
                 }
+                self.start_sfz_regions(channel, note, velocity, false, 0.0);
+            }
+        }
+    }
+    /// Starts a voice for every SFZ region triggered by the note. Release triggered
+    /// regions are started instead if `release` is set, quietened by their `rt_decay`
+    /// according to how long the note was held
+    fn start_sfz_regions(&mut self, channel: u8, note: u8, velocity: f32, release: bool, held_seconds: f32){
+        // Every region the note could trigger shares one random value
+        let random = self.next_random();
+        let channel = channel.min(15) as usize;
+        // Choke first, so regions started by the same note never cut each other off
+        for region_idx in 0..self.instrument.regions.len(){
+            if self.instrument.is_triggered(region_idx, release, note, velocity, random, &self.cc_state[channel]){
+                self.choke_voices(self.instrument.regions[region_idx].choke_group);
+            }
+        }
+        for region_idx in 0..self.instrument.regions.len(){
+            // Conditional filters
+            if !self.instrument.is_triggered(region_idx, release, note, velocity, random, &self.cc_state[channel]){
+                continue;
             }
+            if release{
+                // There's no note off to come, so release samples play to their end
+                let gain = self.instrument.regions[region_idx].release_gain(held_seconds);
+                self.start_sfz_voice(region_idx, note, velocity, gain, true);
+            }else{
+                self.start_sfz_voice(region_idx, note, velocity, 1.0, false);
+            }
+        }
+        self.instrument.advance_sequences(release, note, velocity);
+    }
+    /// Starts a voice playing an SFZ region. One shot voices ignore note offs
+    fn start_sfz_voice(&mut self, region_idx: usize, note: u8, velocity: f32, amplitude: f32, one_shot: bool){
+        let voice_id = self.get_voice_id();
+        // Regions are borrowed rather than cloned, so only reference counts change here
+        let region = &self.instrument.regions[region_idx];
+        let voice = &mut self.warp_voices[voice_id];
+        voice.set_internal_buffer(region.sample.buffer.clone(), region.sample.sample_rate/self.sample_rate);
+        configure_sfz_voice(voice, region, self.warp_params);
+        // SFZ's default key center, so nothing is left over from the warp sampler
+        voice.base_midi = region.pitch_keycenter.unwrap_or(60);
+        if one_shot{
+            voice.set_one_shot(true);
+        }
+        voice.set_amplitude(amplitude * region.gain(note, velocity));
+        voice.note_on(note, velocity);
+    }
+    /// Updates the value (0-1) of a MIDI CC. In Sfz mode this starts any region whose
+    /// `on_locc`/`on_hicc` range the CC has just moved into
+    pub fn control_change(&mut self, channel: u8, cc: u8, value: f32){
+        let channel = channel.min(15) as usize;
+        let previous = std::mem::replace(&mut self.cc_state[channel][cc.min(127) as usize], value);
+        if self.sampler_mode != SamplerMode::Sfz{
+            return;
+        }
+        let random = self.next_random();
+        for region_idx in 0..self.instrument.regions.len(){
+            if !self.instrument.is_cc_triggered(region_idx, cc, previous, value, random, &self.cc_state[channel]){
+                continue;
+            }
+            // There is no key involved, so the region plays at its own pitch
+            let region = &self.instrument.regions[region_idx];
+            let note = region.pitch_keycenter.unwrap_or(region.lokey);
+            self.choke_voices(region.choke_group);
+            self.start_sfz_voice(region_idx, note, 1.0, 1.0, true);
+        }
+    }
+    /// Cuts off every voice that is choked by the given group, according to its
+    /// off mode. A group of 0 chokes nothing
+    fn choke_voices(&mut self, group: i32){
+        if group == 0{
+            return;
+        }
+        for voice in self.warp_voices.iter_mut(){
+            voice.choke(group);
+        }
+        for (_name,_sr_scalar,_buff,voice) in self.sound_bank.values_mut(){
+            voice.choke(group);
         }
     }
+    /// Returns the active SFZ keyswitch, if the loaded instrument uses them
+    pub fn active_keyswitch(&self)->Option<u8>{
+        match self.sampler_mode {
+            SamplerMode::Sfz => self.instrument.active_keyswitch(),
+            _ => None,
+        }
+    }
+    /// Sets the seed of the random values used to pick SFZ regions, so renders can be
+    /// reproduced exactly
+    pub fn set_random_seed(&mut self, seed: u64){
+        // Xorshift gets stuck on a state of zero
+        self.random_state = seed ^ 0x9E37_79B9_7F4A_7C15;
+        if self.random_state == 0{
+            self.random_state = 1;
+        }
+    }
+    /// Stops every voice and clears the held keys, round robins, keyswitches and CCs,
+    /// then reseeds the random values, so the next render plays exactly like the first.
+    /// Doesn't allocate, so it's safe to call on the audio thread
+    pub fn reset(&mut self, seed: u64){
+        for voice in self.warp_voices.iter_mut(){
+            voice.adsr.reset();
+        }
+        for (_, _, _, voice) in self.sound_bank.values_mut(){
+            voice.adsr.reset();
+        }
+        self.instrument.reset_state();
+        self.cc_state = [[0.0; 128]; 16];
+        self.frame_clock = 0;
+        self.set_random_seed(seed);
+    }
+    /// Returns the next random value between 0 and 1 (xorshift64*)
+    fn next_random(&mut self)->f32{
+        self.random_state ^= self.random_state >> 12;
+        self.random_state ^= self.random_state << 25;
+        self.random_state ^= self.random_state >> 27;
+        let value = self.random_state.wrapping_mul(0x2545_F491_4F6C_DD1D);
+        (value >> 40) as f32 / (1_u64 << 24) as f32
+    }
     /// Triggers a note off message
-    pub fn note_off(&mut self, note: u8){
+    pub fn note_off(&mut self, channel: u8, note: u8){
         match self.sampler_mode {
             SamplerMode::Warp =>{
                 for voice in self.warp_voices.iter_mut(){
@@ -254,12 +585,16 @@ impl SamplerEngine{
                 }               
             },
             SamplerMode::Sfz =>{
+                // Layered regions each have their own voice, so all of them are released
                 for voice in self.warp_voices.iter_mut(){
                     if voice.midi_note == note{
                         voice.note_off();
-                        break;
                     }
                 }
+                if let Some((pressed_frame, velocity)) = self.instrument.key_up(note){
+                    let held_seconds = self.frame_clock.saturating_sub(pressed_frame) as f32 / self.sample_rate;
+                    self.start_sfz_regions(channel, note, velocity, true, held_seconds);
+                }
             }
         }
     }
@@ -278,6 +613,15 @@ impl SamplerEngine{
             eprintln!("Entry for note {} does not exist in sound bank", note_of_assigned);
         }
     }
+    /// Sets the choke group of the given assigned note, the group that cuts it off,
+    /// and how it is cut off. Groups of 0 mean none
+    pub fn set_choke_assign(&mut self, choke_group: i32, off_by: i32, off_mode: OffMode, note_of_assigned: u8){
+        if let Some((_file_name, _sr_scalar, _buff, voice)) = self.sound_bank.get_mut(&note_of_assigned) {
+            voice.set_choke(choke_group, off_by, off_mode);
+        } else {
+            eprintln!("Entry for note {} does not exist in sound bank", note_of_assigned);
+        }
+    }
     /// Returns attack, decay, sustain, release values for the warping sampler
     /// 
     /// Returns tuple in format: (attack,decay,sustain,release)
@@ -295,15 +639,10 @@ impl SamplerEngine{
         }
     }
     /// Sets the max number of voices in the warp sampler
-    pub fn set_num_voices(&mut self, mut num_voices: u8){
-        if num_voices < 1{
-            num_voices = 1;
-        }else if num_voices > 24{
-            num_voices = 24;
-        }
+    pub fn set_num_voices(&mut self, num_voices: u8){
+        let num_voices = num_voices.clamp(1, 24);
         self.num_voices = num_voices;
-        self.warp_voices.resize(num_voices as usize, 
-            SamplerVoice::new(self.num_channels,self.sample_rate, 64,VoiceType::Warp));
+        self.warp_voices.resize(num_voices as usize, self.voice_template.clone());
     }
     /// Returns the number of voices available for the warping sampler
     pub fn get_num_voices(&mut self)->u8{
@@ -312,9 +651,16 @@ impl SamplerEngine{
     /// Sets the sampler mode (Warp, Assign, Sfz)
     pub fn set_mode(&mut self, mode: SamplerMode){
         self.sampler_mode = mode;
+        if self.sampler_mode == SamplerMode::Warp{
+            self.apply_warp_root();
+        }
+        self.refresh_warp_params();
     }
     /// Sets the note for the warping to be based on
     pub fn set_warp_base(&mut self, base_note: u8){
+        if self.sampler_mode == SamplerMode::Warp{
+            self.voice_template.set_base_midi(base_note);
+        }
         for voice in self.warp_voices.iter_mut(){
             match self.sampler_mode {
                 SamplerMode::Warp => {voice.set_base_midi(base_note);},
@@ -324,16 +670,16 @@ impl SamplerEngine{
         }
     }
     /// Returns the internal buffer for the warping sampler for use in the gui
-    pub fn get_warp_buffer(& self)-> RingBuffer<f32>{
+    pub fn get_warp_buffer(& self)-> SampleBuffer{
         self.warp_buffer.clone()
     }
     /// Returns the buffer for the sample assigned to the given note
-    pub fn get_assign_buffer(&mut self, note_of_assigned: u8 )->RingBuffer<f32>{
-        if let Some((_file_name, _sr_scalar, buff, voice)) = self.sound_bank.get_mut(&note_of_assigned) {
+    pub fn get_assign_buffer(&mut self, note_of_assigned: u8 )->SampleBuffer{
+        if let Some((_file_name, _sr_scalar, buff, _voice)) = self.sound_bank.get_mut(&note_of_assigned) {
             // Entry exists, update the points
             buff.clone()
         } else {
-            RingBuffer::<f32>::new(1)
+            SampleBuffer::new(self.num_channels, 1)
         }
     }
     /// Sets the start and end points for each of the voices for the warping sampler
@@ -346,7 +692,8 @@ impl SamplerEngine{
             match self.sampler_mode {
                 SamplerMode::Warp => {voice.set_start_and_end_point(start_point, end_point, self.warp_buffer.capacity());},
                 SamplerMode::Assign => {},
-                SamplerMode::Sfz => {voice.set_start_and_end_point(start_point, end_point, voice.internal_buffer.capacity());}
+                // SFZ voices are set up from their region when they start
+                SamplerMode::Sfz => {}
             }
         }
     }
@@ -394,7 +741,7 @@ impl SamplerEngine{
             match self.sampler_mode {
                 SamplerMode::Warp => {voice.set_sus_points(start_point, end_point, self.warp_buffer.capacity());},
                 SamplerMode::Assign => {},
-                SamplerMode::Sfz => {voice.set_sus_points(start_point, end_point, voice.internal_buffer.capacity());}
+                SamplerMode::Sfz => {}
             }
         }
     }
@@ -408,6 +755,30 @@ impl SamplerEngine{
             SamplerMode::Sfz => {(0.0,0.0)}
         }
     }
+    /// Returns the base note, tuning, play range and sustain loop of the warp sampler,
+    /// for writing into the sample's file. Doesn't allocate
+    pub fn warp_export(&mut self)->WarpExport{
+        let length = self.warp_buffer.capacity();
+        let to_frame = |percent: f32| (percent * 0.01 * length as f32).round().clamp(0.0, length as f32) as u32;
+        let (_, fine_tune) = self.warp_root();
+        let voice = &mut self.warp_voices[0];
+        let (start, end) = voice.get_points(length);
+        let (sus_start, sus_end) = voice.get_sus_points(length);
+        let (sus_start, sus_end) = (to_frame(sus_start), to_frame(sus_end));
+        // The voice's loop ends before its end point, the file's on its last frame
+        let sustain_loop = match voice.get_sus_loop_mode(){
+            SustainModes::NoLoop => None,
+            _ if sus_end <= sus_start => None,
+            mode => Some(SampleLoop{ start: sus_start, end: sus_end - 1, mode }),
+        };
+        WarpExport{
+            root_note: voice.base_midi,
+            fine_tune,
+            start: to_frame(start.min(end)),
+            end: to_frame(start.max(end)),
+            sustain_loop,
+        }
+    }
     /// Sets the start and end points of the assigned buffer's sustain looping. Values will be clamped
     /// within start and end points of the sample as a whole
     pub fn set_sus_points_assign(&mut self, start_point: f32, end_point: f32, note_of_assigned: u8){
@@ -433,7 +804,11 @@ impl SamplerEngine{
     /// Sets the sustain looping mode for the warping sampler
     pub fn set_sus_looping_warp(&mut self, mode: SustainModes){
         for voice in self.warp_voices.iter_mut(){
-            voice.set_sus_loop_mode(mode);
+            match self.sampler_mode {
+                SamplerMode::Warp => {voice.set_sus_loop_mode(mode);},
+                SamplerMode::Assign => {},
+                SamplerMode::Sfz => {}
+            }
         }
     }
     /// Sets the sustain looping mode for the assign sampler
@@ -462,6 +837,21 @@ impl SamplerEngine{
             eprintln!("Entry for note {} does not exist in sound bank", note_of_assigned);
         }
     }
+    /// Sets the interpolation kernel used by the warp sampler's voices
+    pub fn set_interpolation_warp(&mut self, interpolation: Interpolation){
+        for voice in self.warp_voices.iter_mut(){
+            voice.set_interpolation(interpolation);
+        }
+    }
+    /// Sets the interpolation kernel used by the voice assigned to the given note
+    pub fn set_interpolation_assign(&mut self, interpolation: Interpolation, note_of_assigned: u8){
+        if let Some((_file_name, _sr_scalar, _buff, voice)) = self.sound_bank.get_mut(&note_of_assigned) {
+            voice.set_interpolation(interpolation);
+        } else {
+            // Entry does not exist, handle the error (e.g., log an error message)
+            eprintln!("Entry for note {} does not exist in sound bank", note_of_assigned);
+        }
+    }
     /// Chooses a voice and steals the quietest one
     fn get_voice_id(&mut self)-> usize{
         for (voice_id, voice) in self.warp_voices.iter_mut().enumerate() {
@@ -501,108 +891,686 @@ impl SamplerEngine{
 
 }
 
-/// Fills a buffer with a file from a path
-fn fill_warp_buffer(buffer: &mut RingBuffer<f32>, path: &str) ->f32{
-    if let Ok(mut reader) = hound::WavReader::open(path){
-        let sample_format = reader.spec().sample_format;
-    let sample_rate = reader.spec().sample_rate as f32;
-    let length = reader.len();
-    buffer.resize(length as usize, 0.0);
-    buffer.set_write_index(0);
-    // Determine the conversion factor based on sample format
-    let conversion_factor = match sample_format {
-        SampleFormat::Float => 1.0, // No conversion needed
-        SampleFormat::Int => {
-            match reader.spec().bits_per_sample {
-                8 => 1.0 / (i8::MAX as f32),
-                16 => 1.0 / (i16::MAX as f32),
-                24 => 1.0 / (8388608 as f32), 
-                _ => panic!("Unsupported bit depth"),
-            }
+/// Sets up the playback range and looping of an SFZ voice from its region.
+/// 
+/// Regions play the whole sample and only loop if the sample file has a loop, as the
+/// SFZ format specifies. The envelope falls back to the warp parameters where the
+/// region doesn't set it
+fn configure_sfz_voice(voice: &mut SamplerVoice, region: &SfzRegion, warp_params: Option<WarpParams>){
+    let capacity = voice.internal_buffer.capacity() as f32;
+    voice.clear_region_settings();
+    let envelope = region.ampeg;
+    let (attack, decay, sustain, release) = warp_params
+        .map_or(SFZ_ENVELOPE, |params| (params.attack, params.decay, params.sustain, params.release));
+    voice.set_adsr(
+        envelope.attack.unwrap_or(attack),
+        envelope.decay.unwrap_or(decay),
+        envelope.sustain.map_or(sustain, |sustain| sustain * 0.01),
+        envelope.release.unwrap_or(release),
+    );
+    voice.adsr.set_delay(envelope.delay.unwrap_or(0.0));
+    voice.adsr.set_hold(envelope.hold.unwrap_or(0.0));
+    voice.set_tuning(region.tuning(), region.pitch_keytrack * 0.01);
+    voice.set_stereo(region.pan, region.width, region.position);
+    let start = region.offset.map_or(0.0, |offset| offset as f32);
+    // SFZ end points are the last frame played, so one is added
+    let end = region.end.map_or(capacity, |end| (end + 1) as f32);
+    voice.set_play_range(start.min(capacity), end.min(capacity));
+    voice.set_choke(region.choke_group, region.off_by, region.off_mode);
+    let sample_loop = region.sample.sustain_loop;
+    let loop_mode = region.loop_mode
+        .unwrap_or(if sample_loop.is_some() {LoopMode::LoopContinuous} else {LoopMode::NoLoop});
+    match loop_mode{
+        LoopMode::NoLoop => voice.set_sus_loop_mode(SustainModes::NoLoop),
+        LoopMode::OneShot => {
+            voice.set_sus_loop_mode(SustainModes::NoLoop);
+            voice.set_one_shot(true);
+        },
+        LoopMode::LoopSustain => voice.set_sus_loop_mode(SustainModes::LoopWrap),
+        LoopMode::LoopContinuous => {
+            voice.set_sus_loop_mode(SustainModes::LoopWrap);
+            voice.set_loop_continuous(true);
         }
-    };
-    match sample_format{
-        SampleFormat::Float => {
-            let mut samples = reader.samples::<f32>();
-            for _ in 0..(length) {
-                if let Some(sample) = samples.next() {
-                    if let Ok(sample_value) = sample {
-                        let sample_float = sample_value * conversion_factor;
-                         buffer.push(sample_float);
-                    }
-                }
-            }
-        }, 
-        SampleFormat::Int => {
-            let mut samples = reader.samples::<i32>();
-            for _ in 0..(length) {
-                
-                if let Some(sample) = samples.next() {
-                    if let Ok(sample_value) = sample {
-                        let sample_float = (sample_value as f32) * conversion_factor;
-                        buffer.push(sample_float);
-                    }
-                }
+    }
+    let loop_start = region.loop_start.or(sample_loop.map(|sample_loop| sample_loop.start))
+        .map_or(0.0, |start| start as f32);
+    let loop_end = region.loop_end.or(sample_loop.map(|sample_loop| sample_loop.end))
+        .map_or(capacity, |end| (end + 1) as f32);
+    voice.set_loop_range(loop_start, loop_end);
+}
+/// Turns the result of loading a file into SFZ regions into what `load_instrument`
+/// returns. If nothing could be loaded, the warnings are added to the error message
+/// since they are what explain why
+fn loaded_sfz<W: fmt::Display, E: fmt::Display>(
+    loaded: Result<(SfzInstrument, Vec<W>), E>,
+    error_warnings: impl Fn(&E)->&[W],
+)->Result<(LoadedInstrument, Vec<String>), String>{
+    match loaded{
+        Ok((instrument, warnings)) => {
+            let warnings = warnings.iter().map(|warning| warning.to_string()).collect();
+            Ok((LoadedInstrument::Sfz(instrument), warnings))
+        },
+        Err(error) => {
+            let mut message = error.to_string();
+            for warning in error_warnings(&error).iter(){
+                message.push_str(&format!("\n{}", warning));
             }
-        }
+            Err(message)
+        },
     }
-    sample_rate as f32
-    }else {
-        dbg!("File Failed to load");
-        44100.0
+}
+/// Decodes an audio, sfz, sf2 or Decent Sampler file so it can be handed to `swap_instrument`. 
+/// `sf2_preset` picks which preset of a SoundFont is loaded.
+/// 
+/// Returns the loaded file with any warnings to show the user, or the reason it
+/// couldn't be loaded. Reads from disk and allocates, so this must never be called
+/// on the audio thread
+pub fn load_instrument(path: &str, sf2_preset: PresetNumber)->Result<(LoadedInstrument, Vec<String>), String>{
+    let extension = Path::new(path).extension()
+        .map(|extension| extension.to_string_lossy().to_lowercase())
+        .unwrap_or_default();
+    match extension.as_str(){
+        "sfz" => loaded_sfz(SfzInstrument::from_file(Path::new(path)), SfzError::warnings),
+        "sf2" => loaded_sfz(load_sf2(Path::new(path), sf2_preset), Sf2Error::warnings),
+        "dspreset" => loaded_sfz(load_dspreset(Path::new(path)), DsError::warnings),
+        "dslibrary" => loaded_sfz(load_dslibrary(Path::new(path)), DsError::warnings),
+        // Anything else is played in Warp mode if it's audio, whatever its extension says
+        _ => {
+            let mut sample = create_buffer(path).map_err(|error| error.to_string())?;
+            sample.detect_missing_root();
+            let LoadedSample{ mut buffer, sample_rate, metadata } = sample;
+            buffer.build_mip_maps();
+            Ok((LoadedInstrument::Warp { buffer, sample_rate, metadata }, Vec::new()))
+        },
     }
-    
 }
 
-fn create_buffer(path: &str)-> (RingBuffer<f32>,f32){
-    if let Ok(mut reader) = hound::WavReader::open(path){
-        let sample_format = reader.spec().sample_format;
-    let num_channels = reader.spec().channels as usize;
-    let sample_rate = reader.spec().sample_rate as f32;
-    let length = reader.len();
-    let mut buffer = RingBuffer::<f32>::new(length as usize);
-    buffer.set_write_index(0);
-    // Determine the conversion factor based on sample format
-    let conversion_factor = match sample_format {
-        SampleFormat::Float => 1.0, // No conversion needed
-        SampleFormat::Int => {
-            match reader.spec().bits_per_sample {
-                8 => 1.0 / (i8::MAX as f32),
-                16 => 1.0 / (i16::MAX as f32),
-                24 => 1.0 / (8388608 as f32), 
-                _ => panic!("Unsupported bit depth"),
-            }
-        }
+/// Writes the file at `source` to `destination` as a wav with the warp sampler's
+/// root note, loop and play range embedded. Wav audio is copied byte for byte,
+/// anything else is written as 32 bit float
+pub fn export_warp_sample(source: &str, destination: &Path, export: &WarpExport)->Result<(), String>{
+    let source = Path::new(source);
+    let data = std::fs::read(source).map_err(|error| LoadError::Io{ path: source.to_path_buf(), error }.to_string())?;
+    let metadata = export.metadata();
+    let file = match decoder::write_wav_metadata(&data, &metadata){
+        Some(file) => file,
+        None => {
+            let decoded = decoder::decode_bytes(source, data).map_err(|error| error.to_string())?;
+            let wav = decoder::encode_wav(&decoded).map_err(|error| error.to_string())?;
+            decoder::write_wav_metadata(&wav, &metadata).ok_or("Couldn't write the wav")?
+        },
     };
-    match sample_format{
-        SampleFormat::Float => {
-            let mut samples = reader.samples::<f32>();
-            for _ in 0..(length) {
-                if let Some(sample) = samples.next() {
-                    if let Ok(sample_value) = sample {
-                        let sample_float = sample_value * conversion_factor;
-                         buffer.push(sample_float);
-                    }
-                }
+    std::fs::write(destination, file)
+        .map_err(|error| format!("Couldn't write {}: {}", destination.display(), error))
+}
+
+/// A decoded audio file, its channels split apart
+pub struct LoadedSample{
+    pub buffer: SampleBuffer,
+    pub sample_rate: f32,
+    pub metadata: SampleMetadata,
+}
+
+impl LoadedSample{
+    /// Listens for the pitch of a sample whose file doesn't say what its root note is,
+    /// taking it as the root note and tuning if one is found
+    pub fn detect_missing_root(&mut self){
+        if self.metadata.root_note.is_none(){
+            if let Some((root_note, fine_tune)) = pitch::detect_root(&self.buffer, self.sample_rate){
+                self.metadata.root_note = Some(root_note);
+                self.metadata.fine_tune = fine_tune;
             }
-        }, 
-        SampleFormat::Int => {
-            let mut samples = reader.samples::<i32>();
-            for _ in 0..(length) {
-                
-                if let Some(sample) = samples.next() {
-                    if let Ok(sample_value) = sample {
-                        let sample_float = (sample_value as f32) * conversion_factor;
-                        buffer.push(sample_float);
-                    }
-                }
+        }
+    }
+}
+
+/// Decodes an audio file in any supported format into a new buffer, splitting
+/// interleaved channels apart
+pub fn create_buffer(path: &str)->Result<LoadedSample, LoadError>{
+    let decoded = decoder::decode_file(Path::new(path))?;
+    Ok(LoadedSample{
+        buffer: SampleBuffer::from_interleaved(&decoded.samples, decoded.num_channels),
+        sample_rate: decoded.sample_rate,
+        metadata: decoded.metadata,
+    })
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+    use crate::sample_pool::{PooledSample, SamplePool};
+    use crate::sfz::{Trigger, CcRange};
+    fn test_params()->WarpParams{
+        WarpParams{
+            num_voices: 4,
+            attack: 0.0,
+            decay: 0.0,
+            sustain: 1.0,
+            release: 0.0,
+            start_point: 0.0,
+            end_point: 100.0,
+            sus_start: 40.0,
+            sus_end: 60.0,
+            sus_mode: SustainModes::NoLoop,
+            fade_time: 0.0,
+            interpolation: Interpolation::Linear,
+        }
+    }
+    fn test_region(note: u8, samples: &[f32], sample_rate: f32)->SfzRegion{
+        let mut region = SfzRegion::new(PooledSample{
+            buffer: Arc::new(SampleBuffer::from_interleaved(samples, 1)),
+            sample_rate,
+            sustain_loop: None,
+        });
+        region.lokey = note;
+        region.hikey = note;
+        region.pitch_keycenter = Some(note);
+        region
+    }
+    /// Plays the note once per block and returns the first sample of each block
+    fn play_repeatedly(engine: &mut SamplerEngine, note: u8, times: usize)->Vec<f32>{
+        let mut hits = Vec::new();
+        for _ in 0..times{
+            let events = [
+                EngineEvent::NoteOn { timing: 0, channel: 0, note, velocity: 1.0 },
+                EngineEvent::NoteOff { timing: 1, channel: 0, note },
+            ];
+            let mut output = [0.0; 4];
+            engine.render(&mut [&mut output[..]], &events);
+            hits.push(output[0]);
+        }
+        hits
+    }
+    #[test]
+    fn test_event_timing(){
+        let mut engine = SamplerEngine::new(44100.0, 2);
+        engine.set_warp_params(test_params());
+        engine.set_warp_base(60);
+        engine.set_warp_buffer(SampleBuffer::from_interleaved(&vec![1.0; 4096], 1), 44100.0);
+        let events = [
+            EngineEvent::NoteOn { timing: 100, channel: 0, note: 60, velocity: 1.0 },
+            EngineEvent::NoteOn { timing: 317, channel: 0, note: 60, velocity: 1.0 },
+        ];
+        let mut left = vec![0.0; 512];
+        let mut right = vec![0.0; 512];
+        engine.render(&mut [&mut left[..], &mut right[..]], &events);
+        for output in [&left, &right]{
+            // Each note adds a constant 1.0 from the frame it starts on
+            assert!(output[..100].iter().all(|sample| *sample == 0.0));
+            assert!(output[100..317].iter().all(|sample| *sample == 1.0));
+            assert!(output[317..].iter().all(|sample| *sample == 2.0));
+        }
+    }
+    #[test]
+    fn test_warp_metadata(){
+        let mut engine = SamplerEngine::new(44100.0, 2);
+        engine.set_warp_params(test_params());
+        let metadata = SampleMetadata{
+            root_note: Some(48),
+            sustain_loop: Some(SampleLoop{ start: 250, end: 749, mode: SustainModes::LoopBounce }),
+            ..SampleMetadata::default()
+        };
+        let buffer = SampleBuffer::from_interleaved(&[0.5; 1000], 1);
+        engine.swap_instrument(LoadedInstrument::Warp{ buffer, sample_rate: 44100.0, metadata });
+        assert_eq!(engine.warp_voices[0].base_midi, 48);
+        assert_eq!(engine.get_sus_points_warp(), (25.0, 75.0));
+        // Re-sending the same params keeps the file's loop, moving a sustain control doesn't
+        engine.set_warp_params(WarpParams{ attack: 0.1, ..test_params() });
+        assert_eq!(engine.get_sus_points_warp(), (25.0, 75.0));
+        engine.set_warp_params(WarpParams{ sus_start: 30.0, ..test_params() });
+        let (sus_start, sus_end) = engine.get_sus_points_warp();
+        assert!((sus_start - 30.0).abs() < 0.001 && (sus_end - 60.0).abs() < 0.001);
+        // A file without metadata goes back to the defaults
+        let buffer = SampleBuffer::from_interleaved(&[0.5; 1000], 1);
+        let old = engine.swap_instrument(LoadedInstrument::Warp{ buffer, sample_rate: 44100.0, metadata: SampleMetadata::default() });
+        assert!(matches!(old, LoadedInstrument::Warp{ metadata: SampleMetadata{ root_note: Some(48), .. }, .. }));
+        assert_eq!(engine.warp_voices[0].base_midi, 60);
+    }
+    #[test]
+    fn test_warp_export(){
+        let mut engine = SamplerEngine::new(44100.0, 1);
+        engine.set_warp_params(WarpParams{ start_point: 10.0, sus_mode: SustainModes::LoopWrap, ..test_params() });
+        engine.set_warp_base(64);
+        engine.set_warp_buffer(SampleBuffer::from_interleaved(&[0.25; 1000], 1), 44100.0);
+        let export = engine.warp_export();
+        assert_eq!(export.root_note, 64);
+        assert_eq!((export.start, export.end), (100, 1000));
+        assert_eq!(export.sustain_loop, Some(SampleLoop{ start: 400, end: 599, mode: SustainModes::LoopWrap }));
+
+        let dir = std::env::temp_dir().join("rust_sampler_export_test");
+        std::fs::create_dir_all(&dir).unwrap();
+        let spec = hound::WavSpec{ channels: 1, sample_rate: 44100, bits_per_sample: 16, sample_format: hound::SampleFormat::Int };
+        let source = dir.join("source.wav");
+        let mut writer = hound::WavWriter::create(&source, spec).unwrap();
+        for i in 0..1000{
+            writer.write_sample(i as i16 * 7).unwrap();
+        }
+        writer.finalize().unwrap();
+        let destination = dir.join("exported.wav");
+        export_warp_sample(source.to_str().unwrap(), &destination, &export).unwrap();
+        // Loading the exported file plays it the same way
+        let (loaded, _) = load_instrument(destination.to_str().unwrap(), PresetNumber::default()).unwrap();
+        let LoadedInstrument::Warp{ metadata, .. } = loaded else { panic!("expected a warp sample") };
+        assert_eq!(metadata.root_note, Some(64));
+        assert_eq!(metadata.sustain_loop, export.sustain_loop);
+        assert_eq!(metadata.cues, [100, 1000]);
+        let original: Vec<i16> = hound::WavReader::open(&source).unwrap().into_samples().map(Result::unwrap).collect();
+        let exported: Vec<i16> = hound::WavReader::open(&destination).unwrap().into_samples().map(Result::unwrap).collect();
+        assert_eq!(original, exported);
+    }
+    #[test]
+    fn test_root_detection(){
+        let dir = std::env::temp_dir().join("rust_sampler_root_test");
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("a3.wav");
+        let spec = hound::WavSpec{ channels: 1, sample_rate: 44100, bits_per_sample: 32, sample_format: hound::SampleFormat::Float };
+        let mut writer = hound::WavWriter::create(&path, spec).unwrap();
+        for i in 0..44100{
+            writer.write_sample((std::f32::consts::TAU * 220.0 * i as f32 / 44100.0).sin() * 0.5).unwrap();
+        }
+        writer.finalize().unwrap();
+        let mut engine = SamplerEngine::new(44100.0, 2);
+        engine.set_warp_params(test_params());
+        let (loaded, _) = load_instrument(path.to_str().unwrap(), PresetNumber::default()).unwrap();
+        engine.swap_instrument(loaded);
+        assert_eq!(engine.warp_voices[0].base_midi, 57);
+        // Choosing the root note by hand wins until it's handed back
+        engine.set_root_override(Some(50));
+        assert_eq!(engine.warp_voices[0].base_midi, 50);
+        assert_eq!(engine.warp_export().fine_tune, 0.0);
+        engine.set_root_override(None);
+        assert_eq!(engine.warp_voices[0].base_midi, 57);
+        // Assigned samples keep their key, only getting the fine tune
+        engine.set_mode(SamplerMode::Assign);
+        engine.assign_file_to_midi(path.to_str().unwrap(), 40).unwrap();
+        assert_eq!(engine.sound_bank[&40].3.base_midi, 40);
+    }
+    #[test]
+    fn test_render_range(){
+        let ramp: Vec<f32> = (0..4096).map(|i| i as f32 * 0.0001).collect();
+        let events = [
+            EngineEvent::NoteOn { timing: 10, channel: 0, note: 60, velocity: 1.0 },
+            EngineEvent::NoteOn { timing: 70, channel: 0, note: 67, velocity: 1.0 },
+            EngineEvent::NoteOff { timing: 90, channel: 0, note: 60 },
+        ];
+        let mut outputs = Vec::new();
+        for pieces in [vec![0, 128], vec![0, 32, 64, 70, 96, 128]]{
+            let mut engine = SamplerEngine::new(44100.0, 1);
+            engine.set_warp_params(test_params());
+            engine.set_warp_base(60);
+            engine.set_warp_buffer(SampleBuffer::from_interleaved(&ramp, 1), 44100.0);
+            let mut output = vec![1.0; 128];
+            let mut next_event = 0;
+            for range in pieces.windows(2){
+                let count = events[next_event..].iter().filter(|event| (event.timing() as usize) < range[1]).count();
+                engine.render_range(&mut [&mut output[..]], &events[next_event..next_event + count], range[0], range[1]);
+                next_event += count;
             }
+            outputs.push(output);
+        }
+        // Rendering a block in pieces sounds the same as rendering it at once
+        assert_eq!(outputs[0], outputs[1]);
+    }
+    #[test]
+    fn test_swap_instrument(){
+        let mut engine = SamplerEngine::new(44100.0, 1);
+        engine.set_warp_params(test_params());
+        let note_on = [EngineEvent::NoteOn { timing: 0, channel: 0, note: 60, velocity: 1.0 }];
+        let mut output = vec![0.0; 64];
+        let warp = SampleBuffer::from_interleaved(&[0.5; 100], 1);
+        engine.swap_instrument(LoadedInstrument::Warp{ buffer: warp, sample_rate: 22050.0, metadata: SampleMetadata::default() });
+        let region = test_region(60, &[0.25; 1000], 44100.0);
+        let region_buffer = region.sample.buffer.clone();
+        engine.swap_instrument(LoadedInstrument::Sfz(SfzInstrument::new(vec![region], SamplePool::new())));
+        // A sounding region hands its sample to a voice
+        engine.render(&mut [&mut output[..]], &note_on);
+        assert_eq!(output[10], 0.25);
+        assert!(Arc::strong_count(&region_buffer) > 2);
+
+        // Each kind of instrument swaps out the last one of its kind, whole, so it can
+        // be freed off the audio thread
+        let warp = SampleBuffer::from_interleaved(&[0.75; 200], 1);
+        let old = engine.swap_instrument(LoadedInstrument::Warp{ buffer: warp, sample_rate: 44100.0, metadata: SampleMetadata::default() });
+        let LoadedInstrument::Warp{ buffer, sample_rate, .. } = old else { panic!("expected the old warp sample back") };
+        assert_eq!((buffer.capacity(), buffer.channel(0).get(10), sample_rate), (100, 0.5, 22050.0));
+        engine.render(&mut [&mut output[..]], &note_on);
+        assert_eq!(output[10], 0.75);
+
+        let old = engine.swap_instrument(LoadedInstrument::Sfz(SfzInstrument::new(Vec::new(), SamplePool::new())));
+        let LoadedInstrument::Sfz(instrument) = old else { panic!("expected the old sfz instrument back") };
+        // Once the old instrument is dropped only this test holds the region's sample,
+        // so no voice still points at it
+        drop(instrument);
+        assert_eq!(Arc::strong_count(&region_buffer), 1);
+    }
+    #[test]
+    fn test_sfz_sample_rates(){
+        let mut engine = SamplerEngine::new(44100.0, 1);
+        engine.set_warp_params(test_params());
+        let ramp: Vec<f32> = (0..1000).map(|i| i as f32 * 0.001).collect();
+        let instrument = SfzInstrument::new(vec![
+            test_region(60, &ramp, 44100.0),
+            test_region(62, &[0.0; 1000], 22050.0),
+        ], SamplePool::new());
+        engine.swap_instrument(LoadedInstrument::Sfz(instrument));
+        let events = [
+            EngineEvent::NoteOn { timing: 0, channel: 0, note: 60, velocity: 1.0 },
+            EngineEvent::NoteOn { timing: 0, channel: 0, note: 62, velocity: 1.0 },
+        ];
+        let mut output = vec![0.0; 64];
+        engine.render(&mut [&mut output[..]], &events);
+        // The 22.05 kHz region must not slow down the 44.1 kHz one
+        for (i, sample) in output.iter().enumerate(){
+            assert!((sample - i as f32 * 0.001).abs() < 0.00001);
+        }
+    }
+    #[test]
+    fn test_round_robin(){
+        let mut engine = SamplerEngine::new(44100.0, 1);
+        engine.set_warp_params(test_params());
+        let mut regions = Vec::new();
+        for position in 1..=3{
+            let mut region = test_region(60, &[position as f32; 100], 44100.0);
+            region.seq_length = 3;
+            region.seq_position = position;
+            regions.push(region);
+        }
+        engine.swap_instrument(LoadedInstrument::Sfz(SfzInstrument::new(regions, SamplePool::new())));
+        assert_eq!(play_repeatedly(&mut engine, 60, 6), vec![1.0, 2.0, 3.0, 1.0, 2.0, 3.0]);
+    }
+    #[test]
+    fn test_random_seed(){
+        let instrument = || {
+            let mut low = test_region(60, &[1.0; 100], 44100.0);
+            low.hirand = 0.5;
+            let mut high = test_region(60, &[2.0; 100], 44100.0);
+            high.lorand = 0.5;
+            SfzInstrument::new(vec![low, high], SamplePool::new())
+        };
+        let mut renders = Vec::new();
+        for _ in 0..2{
+            let mut engine = SamplerEngine::new(44100.0, 1);
+            engine.set_warp_params(test_params());
+            engine.swap_instrument(LoadedInstrument::Sfz(instrument()));
+            engine.set_random_seed(1234);
+            renders.push(play_repeatedly(&mut engine, 60, 32));
+        }
+        // Exactly one of the two regions plays each time, in the same order for the same seed
+        assert!(renders[0].iter().all(|hit| *hit == 1.0 || *hit == 2.0));
+        assert!(renders[0].contains(&1.0) && renders[0].contains(&2.0));
+        assert_eq!(renders[0], renders[1]);
+    }
+    #[test]
+    fn test_reset(){
+        let mut regions = Vec::new();
+        for position in 1..=3{
+            let mut region = test_region(60, &[position as f32; 100], 44100.0);
+            region.seq_length = 3;
+            region.seq_position = position;
+            region.sw_last = Some(36);
+            region.sw_default = Some(36);
+            regions.push(region);
+        }
+        let mut staccato = test_region(60, &[4.0; 100], 44100.0);
+        staccato.sw_last = Some(37);
+        let mut close_mic = test_region(62, &[5.0; 100], 44100.0);
+        close_mic.cc_conditions.push(CcRange{ cc: 1, lo: 0.0, hi: 63.0 });
+        let mut far_mic = test_region(62, &[6.0; 100], 44100.0);
+        far_mic.cc_conditions.push(CcRange{ cc: 1, lo: 64.0, hi: 127.0 });
+        let mut low = test_region(64, &[7.0; 100], 44100.0);
+        low.hirand = 0.5;
+        let mut high = test_region(64, &[8.0; 100], 44100.0);
+        high.lorand = 0.5;
+        regions.extend([staccato, close_mic, far_mic, low, high]);
+        let mut engine = SamplerEngine::new(44100.0, 1);
+        engine.set_warp_params(test_params());
+        engine.swap_instrument(LoadedInstrument::Sfz(SfzInstrument::new(regions, SamplePool::new())));
+        // Moves through the round robin, a keyswitch and a CC, leaving a note held
+        let session = |engine: &mut SamplerEngine|{
+            let mut hits = play_repeatedly(engine, 60, 2);
+            hits.extend(play_repeatedly(engine, 62, 1));
+            hits.extend(play_repeatedly(engine, 64, 8));
+            engine.control_change(0, 1, 1.0);
+            hits.extend(play_repeatedly(engine, 62, 1));
+            hits.extend(play_repeatedly(engine, 37, 1));
+            hits.extend(play_repeatedly(engine, 60, 1));
+            engine.note_on(0, 60, 1.0);
+            hits
+        };
+        engine.reset(1234);
+        let first = session(&mut engine);
+        assert_ne!(session(&mut engine), first);
+        engine.reset(1234);
+        let mut output = vec![0.0; 4];
+        engine.render(&mut [&mut output[..]], &[]);
+        // The held note is cut off and everything plays as it did the first time
+        assert_eq!(output, vec![0.0; 4]);
+        assert_eq!(session(&mut engine), first);
+    }
+    #[test]
+    fn test_keyswitches(){
+        let mut engine = SamplerEngine::new(44100.0, 1);
+        engine.set_warp_params(test_params());
+        let mut sustain = test_region(60, &[1.0; 100], 44100.0);
+        sustain.sw_last = Some(36);
+        sustain.sw_default = Some(36);
+        let mut staccato = test_region(60, &[2.0; 100], 44100.0);
+        staccato.sw_last = Some(37);
+        let mut held = test_region(62, &[3.0; 100], 44100.0);
+        held.sw_down = Some(38);
+        engine.swap_instrument(LoadedInstrument::Sfz(SfzInstrument::new(vec![sustain, staccato, held], SamplePool::new())));
+        assert_eq!(engine.active_keyswitch(), Some(36));
+        assert_eq!(play_repeatedly(&mut engine, 60, 1), vec![1.0]);
+        // The keyswitch itself is silent
+        assert_eq!(play_repeatedly(&mut engine, 37, 1), vec![0.0]);
+        assert_eq!(engine.active_keyswitch(), Some(37));
+        assert_eq!(play_repeatedly(&mut engine, 60, 1), vec![2.0]);
+        // sw_down regions only play while their key is held
+        assert_eq!(play_repeatedly(&mut engine, 62, 1), vec![0.0]);
+        engine.note_on(0, 38, 1.0);
+        assert_eq!(play_repeatedly(&mut engine, 62, 1), vec![3.0]);
+    }
+    #[test]
+    fn test_release_trigger(){
+        let mut engine = SamplerEngine::new(1000.0, 1);
+        engine.set_warp_params(test_params());
+        let attack = test_region(60, &[1.0; 100], 1000.0);
+        let mut release = test_region(60, &[2.0; 100], 1000.0);
+        release.trigger = Trigger::Release;
+        release.rt_decay = 20.0;
+        engine.swap_instrument(LoadedInstrument::Sfz(SfzInstrument::new(vec![attack, release], SamplePool::new())));
+        let events = [
+            EngineEvent::NoteOn { timing: 0, channel: 0, note: 60, velocity: 1.0 },
+            EngineEvent::NoteOff { timing: 1000, channel: 0, note: 60 },
+        ];
+        let mut output = vec![0.0; 1010];
+        engine.render(&mut [&mut output[..]], &events);
+        // Only the attack region plays until the note off
+        assert_eq!(output[0], 1.0);
+        assert_eq!(output[999], 0.0);
+        // The note was held for a second, so the release sample is 20 dB down
+        assert!((output[1000] - 0.2).abs() < 1e-4);
+    }
+    #[test]
+    fn test_cc_regions(){
+        let mut engine = SamplerEngine::new(44100.0, 1);
+        engine.set_warp_params(test_params());
+        let mut close_mic = test_region(60, &[1.0; 100], 44100.0);
+        close_mic.cc_conditions.push(CcRange{ cc: 1, lo: 0.0, hi: 63.0 });
+        let mut far_mic = test_region(60, &[2.0; 100], 44100.0);
+        far_mic.cc_conditions.push(CcRange{ cc: 1, lo: 64.0, hi: 127.0 });
+        let mut pedal = test_region(30, &[3.0; 100], 44100.0);
+        pedal.cc_triggers.push(CcRange{ cc: 64, lo: 64.0, hi: 127.0 });
+        engine.swap_instrument(LoadedInstrument::Sfz(SfzInstrument::new(vec![close_mic, far_mic, pedal], SamplePool::new())));
+        assert_eq!(play_repeatedly(&mut engine, 60, 1), vec![1.0]);
+        // CCs are tracked per channel
+        engine.control_change(1, 1, 1.0);
+        assert_eq!(play_repeatedly(&mut engine, 60, 1), vec![1.0]);
+        engine.control_change(0, 1, 1.0);
+        assert_eq!(play_repeatedly(&mut engine, 60, 1), vec![2.0]);
+        // Pressing the pedal fires the pedal region once, moving within the range doesn't
+        let events = [
+            EngineEvent::ControlChange { timing: 0, channel: 0, cc: 64, value: 1.0 },
+            EngineEvent::ControlChange { timing: 1, channel: 0, cc: 64, value: 0.9 },
+        ];
+        let mut output = vec![0.0; 4];
+        engine.render(&mut [&mut output[..]], &events);
+        assert_eq!(output, vec![3.0; 4]);
+    }
+    #[test]
+    fn test_choke_groups(){
+        let mut engine = SamplerEngine::new(1000.0, 1);
+        engine.set_warp_params(test_params());
+        let mut open_hat = test_region(46, &[1.0; 100], 1000.0);
+        open_hat.choke_group = 1;
+        open_hat.off_by = 2;
+        let mut closed_hat = test_region(42, &[2.0; 100], 1000.0);
+        closed_hat.choke_group = 2;
+        engine.swap_instrument(LoadedInstrument::Sfz(SfzInstrument::new(vec![open_hat, closed_hat], SamplePool::new())));
+        let events = [
+            EngineEvent::NoteOn { timing: 0, channel: 0, note: 46, velocity: 1.0 },
+            EngineEvent::NoteOn { timing: 10, channel: 0, note: 42, velocity: 1.0 },
+        ];
+        let mut output = [0.0; 30];
+        engine.render(&mut [&mut output[..]], &events);
+        assert_eq!(output[9], 1.0);
+        // The open hat fades out quickly instead of stopping with a click
+        assert!(output[11] > 2.0 && output[11] < 3.0);
+        assert_eq!(output[20], 2.0);
+    }
+    #[test]
+    fn test_root_override_in_sfz(){
+        let mut engine = SamplerEngine::new(44100.0, 1);
+        engine.set_warp_params(test_params());
+        let ramp: Vec<f32> = (0..1000).map(|i| i as f32 * 0.001).collect();
+        let mut region = test_region(60, &ramp, 44100.0);
+        region.hikey = 72;
+        region.tune = 50.0;
+        region.pitch_keytrack = 0.0;
+        engine.swap_instrument(LoadedInstrument::Sfz(SfzInstrument::new(vec![region], SamplePool::new())));
+        let mut output = vec![0.0; 64];
+        let events = [EngineEvent::NoteOn { timing: 0, channel: 0, note: 67, velocity: 1.0 }];
+        engine.render(&mut [&mut output[..32]], &events);
+        // Changing the warp sample's root note leaves the sounding region alone
+        engine.set_root_override(Some(48));
+        engine.render(&mut [&mut output[32..]], &[]);
+        let voice = &engine.warp_voices[0];
+        assert_eq!((voice.base_midi, voice.get_tuning()), (60, (0.5, 0.0)));
+        // Half a semitone up, whatever the key
+        let rate = 2.0f32.powf(0.5 / 12.0);
+        for (i, sample) in output.iter().enumerate(){
+            assert!((sample - i as f32 * rate * 0.001).abs() < 1e-4);
+        }
+        // The override is still waiting for the warp sampler when it comes back
+        engine.swap_instrument(LoadedInstrument::Warp{
+            buffer: SampleBuffer::from_interleaved(&ramp, 1),
+            sample_rate: 44100.0,
+            metadata: SampleMetadata::default(),
+        });
+        let events = [EngineEvent::NoteOn { timing: 0, channel: 0, note: 60, velocity: 1.0 }];
+        let mut output = [0.0; 16];
+        engine.render(&mut [&mut output[..]], &events);
+        // An octave above the root note of 48
+        for (i, sample) in output.iter().enumerate(){
+            assert!((sample - i as f32 * 0.002).abs() < 1e-4);
+        }
+    }
+    #[test]
+    fn test_region_gain_and_tuning(){
+        let mut engine = SamplerEngine::new(44100.0, 2);
+        engine.set_warp_params(test_params());
+        let ramp: Vec<f32> = (0..100).map(|i| i as f32).collect();
+        let mut region = test_region(60, &ramp, 44100.0);
+        region.volume = -6.0206;
+        region.transpose = 12;
+        region.pan = 100.0;
+        engine.swap_instrument(LoadedInstrument::Sfz(SfzInstrument::new(vec![region], SamplePool::new())));
+        let events = [EngineEvent::NoteOn { timing: 0, channel: 0, note: 60, velocity: 0.5 }];
+        let mut left = [0.0; 4];
+        let mut right = [0.0; 4];
+        engine.render(&mut [&mut left[..], &mut right[..]], &events);
+        // -6 dB, velocity squared and an equal power pan hard right
+        let gain = 0.5 * 0.25 * std::f32::consts::SQRT_2;
+        for idx in 0..4{
+            assert!(left[idx].abs() < 1e-5);
+            // An octave up reads every other frame of the ramp
+            assert!((right[idx] - (idx * 2) as f32 * gain).abs() < 1e-4);
+        }
+    }
+    #[test]
+    fn test_region_delay(){
+        let mut engine = SamplerEngine::new(44100.0, 1);
+        engine.set_warp_params(test_params());
+        let ramp: Vec<f32> = (0..100).map(|i| i as f32 * 0.01).collect();
+        let mut region = test_region(60, &ramp, 44100.0);
+        region.offset = Some(10);
+        region.ampeg.delay = Some(16.5 / 44100.0);
+        engine.swap_instrument(LoadedInstrument::Sfz(SfzInstrument::new(vec![region], SamplePool::new())));
+        let events = [EngineEvent::NoteOn { timing: 0, channel: 0, note: 60, velocity: 1.0 }];
+        let mut output = [0.0; 64];
+        engine.render(&mut [&mut output[..]], &events);
+        // Silent for the delay, then the sample starts from its offset rather than partway in
+        assert!(output[..17].iter().all(|sample| *sample == 0.0));
+        for (frame, sample) in output[17..].iter().enumerate(){
+            assert!((sample - (10 + frame) as f32 * 0.01).abs() < 1e-4);
         }
     }
-    (buffer,sample_rate)
-    }else{
-        dbg!("File Failed to load");
-        (RingBuffer::new(1), 44100.0)
+    #[test]
+    fn test_region_loops(){
+        // Plays a region over a ramp of 100 frames, letting go of the note after 5 frames
+        let play = |region: SfzRegion|{
+            let mut engine = SamplerEngine::new(44100.0, 1);
+            // The warp sampler's points and loop must not leak into the region
+            engine.set_warp_params(WarpParams{ start_point: 50.0, sus_mode: SustainModes::LoopWrap, ..test_params() });
+            engine.swap_instrument(LoadedInstrument::Sfz(SfzInstrument::new(vec![region], SamplePool::new())));
+            let events = [
+                EngineEvent::NoteOn { timing: 0, channel: 0, note: 60, velocity: 1.0 },
+                EngineEvent::NoteOff { timing: 5, channel: 0, note: 60 },
+            ];
+            let mut output = vec![0.0; 64];
+            engine.render(&mut [&mut output[..]], &events);
+            output
+        };
+        let ramp: Vec<f32> = (0..100).map(|i| i as f32 * 0.01).collect();
+        let looped = |loop_mode: Option<LoopMode>|{
+            let mut region = test_region(60, &ramp, 44100.0);
+            region.loop_mode = loop_mode;
+            region.loop_start = Some(20);
+            region.loop_end = Some(29);
+            region.ampeg.release = Some(10.0);
+            region
+        };
+        let near = |sample: f32, expected: f32| (sample - expected).abs() < 0.01;
+        // By default the whole sample plays without looping and stops at the note off
+        let output = play(test_region(60, &ramp, 44100.0));
+        assert!(near(output[0], 0.0) && near(output[4], 0.04));
+        assert!(output[10..].iter().all(|sample| *sample == 0.0));
+        // A loop keeps going through the release only when it is continuous
+        let output = play(looped(Some(LoopMode::LoopContinuous)));
+        assert!(near(output[29], 0.29) && near(output[30], 0.2) && near(output[40], 0.2));
+        let output = play(looped(Some(LoopMode::LoopSustain)));
+        assert!(near(output[30], 0.3) && near(output[40], 0.4));
+        let output = play(looped(Some(LoopMode::NoLoop)));
+        assert!(near(output[30], 0.3));
+        let mut region = looped(Some(LoopMode::OneShot));
+        region.ampeg.release = None;
+        let output = play(region);
+        assert!(near(output[30], 0.3) && near(output[63], 0.63));
+        // Without a loop mode, the region loops only if the sample has a loop
+        let output = play(looped(None));
+        assert!(near(output[30], 0.3));
+        let mut region = test_region(60, &ramp, 44100.0);
+        region.sample.sustain_loop = Some(SampleLoop{ start: 10, end: 19, mode: SustainModes::LoopWrap });
+        region.ampeg.release = Some(10.0);
+        let output = play(region);
+        assert!(near(output[19], 0.19) && near(output[20], 0.1));
+        // The offset and end pick out the part of the sample that is played
+        let mut region = test_region(60, &ramp, 44100.0);
+        region.offset = Some(10);
+        region.end = Some(19);
+        region.ampeg.release = Some(10.0);
+        let output = play(region);
+        assert!(near(output[0], 0.1) && near(output[8], 0.18));
+        assert!(output[10..].iter().all(|sample| *sample == 0.0));
     }
-    
-}
\ No newline at end of file
+}
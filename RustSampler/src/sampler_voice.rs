@@ -1,20 +1,22 @@
-use std::clone;
-use std::fmt;
-use crate::ring_buffer;
+use crate::sample_buffer;
+use crate::ring_buffer::Interpolation;
 use nih_plug::params::enums::Enum;
-use ring_buffer::RingBuffer;
+use sample_buffer::SampleBuffer;
+use std::sync::Arc;
 use crate::adsr;
 use adsr::{Adsr, AdsrState};
 use crate::crossfade;
 use crossfade::Crossfade;
 
+/// How long a voice takes to fade out when it is choked with `OffMode::Fast`, in seconds
+const FAST_OFF_TIME: f32 = 0.005;
+
 #[derive(Clone)]
 pub struct SamplerVoice{
     phase_offset: f32,
     phase_step: f32,
     pub midi_note: u8,
     pub base_midi: u8,
-    num_channels: usize,
     sample_rate: f32,
     pub adsr: Adsr,
     pub sus_is_velo: bool,
@@ -28,7 +30,26 @@ pub struct SamplerVoice{
     fade_time: f32,
     sus_passed: bool,
     voice_type: VoiceType,
-    pub internal_buffer: RingBuffer<f32>
+    interpolation: Interpolation,
+    // Ratio of the internal buffer's sample rate to the output sample rate
+    sr_scalar: f32,
+    one_shot: bool,
+    loop_continuous: bool,
+    // Linear gain applied on top of the envelope
+    amplitude: f32,
+    // Choke group of the voice and the group that cuts it off, 0 being none
+    choke_group: i32,
+    off_by: i32,
+    off_mode: OffMode,
+    // Gain of the fade out after a fast choke, and how much it drops each frame
+    choke_gain: f32,
+    choke_step: f32,
+    // Semitones added to the pitch, and how many semitones it moves per key
+    tuning: f32,
+    pitch_keytrack: f32,
+    // Mixes the sample's left and right channels into the left and right outputs
+    stereo: [[f32; 2]; 2],
+    pub internal_buffer: Arc<SampleBuffer>
 }
 #[derive(Clone, Copy, PartialEq, Enum, Debug)]
 pub enum SustainModes {
@@ -41,6 +62,12 @@ impl SustainModes {
         [SustainModes::NoLoop, SustainModes::LoopWrap, SustainModes::LoopBounce].iter().copied()
     }
 }
+/// How a voice is cut off when a voice in the group that chokes it starts
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum OffMode{
+    Fast, // Fades out over a few milliseconds
+    Normal, // Goes through its own release stage
+}
 #[derive(Clone, Copy, PartialEq)]
 pub enum VoiceType{
     Warp,
@@ -59,7 +86,6 @@ impl SamplerVoice{
             phase_step: 1.0,
             midi_note: 0,
             base_midi: base_midi_,
-            num_channels: num_channesls_,
             sample_rate: sample_rate_,
             adsr: adsr_,
             sus_is_velo: false,
@@ -73,82 +99,126 @@ impl SamplerVoice{
             fade_time: 0.0002,
             sus_passed: false,
             voice_type: voice_type_,
-            internal_buffer: RingBuffer::<f32>::new(1)
+            interpolation: Interpolation::Linear,
+            sr_scalar: 1.0,
+            one_shot: false,
+            loop_continuous: false,
+            amplitude: 1.0,
+            choke_group: 0,
+            off_by: 0,
+            off_mode: OffMode::Fast,
+            choke_gain: 1.0,
+            choke_step: 0.0,
+            tuning: 0.0,
+            pitch_keytrack: 1.0,
+            stereo: [[1.0, 0.0], [0.0, 1.0]],
+            internal_buffer: Arc::new(SampleBuffer::new(num_channesls_, 1))
         }
     }
-    ///Reads from the loaded sample file
+    ///Renders frames `start..end` of the loaded sample file, adding them to `outputs`
     /// Uses the get_frac function in the ring_buffer, which returns the sample
-    /// at a fractional index
-    pub fn process(&mut self, buffer: &mut RingBuffer<f32>, sr_scalar: f32)->f32{
-        self.check_inits(buffer.capacity());
-        let fade_samps = self.fade_time*self.sample_rate;
-        let cross_start;
-        if self.adsr.is_active(){
-            let mut sample = buffer.get_frac(self.phase_offset);
-            if !self.reversed{
-                cross_start = self.sus_end - fade_samps;
-                self.phase_offset += self.phase_step * sr_scalar;
-                if self.sus_mode != SustainModes::NoLoop{
-                    self.sus_logic(&mut sample, cross_start);
-                }
-                if self.phase_offset >= self.end_point{
-                    self.phase_step = 0.0;
-                    self.phase_offset = self.start_point;
-                    return 0.0
-                }
-            }else{     
-                cross_start = self.sus_start + fade_samps;
-                self.phase_offset -= self.phase_step * sr_scalar;
-                if self.sus_mode != SustainModes::NoLoop{
-                    self.sus_logic(&mut sample, cross_start);
-                }
-                if self.phase_offset <= self.end_point{
-                    self.phase_step = 0.0;
-                    self.phase_offset = self.start_point;
-                    return 0.0
-                }
+    /// at a fractional index. Output channel n reads from sample channel n, wrapping
+    /// around so mono files play on every output
+    pub fn render(&mut self, buffer: &SampleBuffer, sr_scalar: f32, outputs: &mut [&mut [f32]], start: usize, end: usize){
+        if !self.adsr.is_active(){
+            self.phase_offset = self.start_point;
+            self.sus_passed = false;
+            return
+        }
+        let capacity = buffer.capacity();
+        for idx in start..end{
+            let rate = (self.phase_step * sr_scalar).abs();
+            if let Some((position, gain)) = self.next_frame(capacity, sr_scalar){
+                self.write_frame(buffer, outputs, idx, position, rate, gain);
             }
-            sample * self.adsr.get_next_sample()
-        }else{
+        }
+    }
+    /// Same as `render`, but reads from the voice's internal buffer at the voice's own
+    /// sample rate ratio
+    pub fn render_sfz(&mut self, outputs: &mut [&mut [f32]], start: usize, end: usize){
+        let sr_scalar = self.sr_scalar;
+        if !self.adsr.is_active(){
             self.phase_offset = self.start_point;
             self.sus_passed = false;
-            0.0
+            return
+        }
+        let capacity = self.internal_buffer.capacity();
+        for idx in start..end{
+            let rate = (self.phase_step * sr_scalar).abs();
+            if let Some((position, gain)) = self.next_frame(capacity, sr_scalar){
+                self.write_frame(&self.internal_buffer, outputs, idx, position, rate, gain);
+            }
         }
     }
-    pub fn process_sfz(&mut self, sr_scalar:f32)->f32{
-        self.check_inits(self.internal_buffer.capacity());
+    /// Adds one frame of the buffer to the outputs. Stereo outputs go through the
+    /// voice's pan and width, any other layout maps sample channels straight across
+    fn write_frame(&self, buffer: &SampleBuffer, outputs: &mut [&mut [f32]], idx: usize, position: f32, rate: f32, gain: f32){
+        if outputs.len() == 2{
+            let left = buffer.get_frac_at_rate(0, position, rate, self.interpolation) * gain;
+            let right = buffer.get_frac_at_rate(1, position, rate, self.interpolation) * gain;
+            for (output, mix) in outputs.iter_mut().zip(self.stereo.iter()){
+                output[idx] += mix[0] * left + mix[1] * right;
+            }
+            return
+        }
+        for (channel, output) in outputs.iter_mut().enumerate(){
+            output[idx] += buffer.get_frac_at_rate(channel, position, rate, self.interpolation) * gain;
+        }
+    }
+    /// Advances the playhead by one frame.
+    /// 
+    /// Returns the position to read from and the gain to apply to every channel,
+    /// or None if the voice is silent for this frame
+    fn next_frame(&mut self, capacity: usize, sr_scalar: f32)->Option<(f32, f32)>{
+        self.check_inits(capacity);
         let fade_samps = self.fade_time*self.sample_rate;
         let cross_start;
         if self.adsr.is_active(){
-            let mut sample = self.internal_buffer.get_frac(self.phase_offset);
+            let position = self.phase_offset;
+            // The sample waits for the envelope's delay rather than playing silently under it
+            if self.adsr.state == AdsrState::Delay{
+                self.adsr.get_next_sample();
+                return Some((position, 0.0))
+            }
+            let mut gain = 1.0;
             if !self.reversed{
                 cross_start = self.sus_end - fade_samps;
                 self.phase_offset += self.phase_step * sr_scalar;
                 if self.sus_mode != SustainModes::NoLoop{
-                    self.sus_logic(&mut sample, cross_start);
+                    self.sus_logic(&mut gain, cross_start);
                 }
                 if self.phase_offset >= self.end_point{
                     self.phase_step = 0.0;
                     self.phase_offset = self.start_point;
-                    return 0.0
+                    // The sample has run out, so the voice is free again
+                    self.adsr.reset();
+                    return None
                 }
             }else{     
                 cross_start = self.sus_start + fade_samps;
                 self.phase_offset -= self.phase_step * sr_scalar;
                 if self.sus_mode != SustainModes::NoLoop{
-                    self.sus_logic(&mut sample, cross_start);
+                    self.sus_logic(&mut gain, cross_start);
                 }
                 if self.phase_offset <= self.end_point{
                     self.phase_step = 0.0;
                     self.phase_offset = self.start_point;
-                    return 0.0
+                    self.adsr.reset();
+                    return None
+                }
+            }
+            if self.choke_step > 0.0{
+                self.choke_gain -= self.choke_step;
+                if self.choke_gain <= 0.0{
+                    self.adsr.reset();
+                    return None
                 }
             }
-            sample * self.adsr.get_next_sample()
+            Some((position, gain * self.amplitude * self.choke_gain * self.adsr.get_next_sample()))
         }else{
             self.phase_offset = self.start_point;
             self.sus_passed = false;
-            0.0
+            None
         }
     }
     ///Sets the midi note for the output
@@ -158,9 +228,11 @@ impl SamplerVoice{
         self.midi_note = note;
         if self.voice_type == VoiceType::Warp{
             let offset = iclamp((note as i8 - self.base_midi as i8)as i32,-127,127);
-            self.phase_step = 2.0_f32.powf(offset as f32 / 12.0);
+            let semitones = offset as f32 * self.pitch_keytrack + self.tuning;
+            self.phase_step = 2.0_f32.powf(semitones / 12.0);
         }else{
-            self.phase_step = 1.0;
+            // Assigned samples play at their own pitch, apart from their tuning
+            self.phase_step = 2.0_f32.powf(self.tuning / 12.0);
         }
     }
     /// Triggers attack on ADSR and starts playback of the audio file
@@ -169,12 +241,16 @@ impl SamplerVoice{
             self.adsr.set_sustain(velocity);
         }
         self.phase_offset = self.start_point;
+        self.choke_gain = 1.0;
+        self.choke_step = 0.0;
         self.set_note(note);
         self.adsr.note_on();
     }
-    /// Triggers release on ADSR
+    /// Triggers release on ADSR. One shot voices ignore this and play to the end
     pub fn note_off(&mut self){
-        self.adsr.note_off()
+        if !self.one_shot{
+            self.adsr.note_off()
+        }
     }
     /// Sets the attack, decay, sustain, and release for the ADSR (in seconds)
     pub fn set_adsr(&mut self, attack_:f32, decay_:f32, sustain_:f32, release_:f32){
@@ -212,6 +288,87 @@ impl SamplerVoice{
         self.set_start_point(start_point,length);
         self.set_end_point(end_point,length);
     }
+    /// Sets the start and end of playback in frames rather than percent. 
+    /// 
+    /// If the start is greater than the end, the playback will be reversed
+    pub fn set_play_range(&mut self, start: f32, end: f32){
+        self.start_point = start;
+        self.end_point = end;
+        self.reversed = self.start_point > self.end_point;
+    }
+    /// Sets the sustain loop in frames rather than percent. The loop is clamped within
+    /// the playback range
+    pub fn set_loop_range(&mut self, start: f32, end: f32){
+        let (low, high) = if self.reversed {(self.end_point, self.start_point)} else {(self.start_point, self.end_point)};
+        self.sus_start = fclamp(start, low, high);
+        self.sus_end = fclamp(end, self.sus_start, high);
+    }
+    /// Sets whether the voice ignores note offs and plays until the end of the sample
+    pub fn set_one_shot(&mut self, one_shot: bool){
+        self.one_shot = one_shot;
+    }
+    /// Sets the linear gain of the voice, applied on top of the envelope
+    pub fn set_amplitude(&mut self, amplitude: f32){
+        self.amplitude = amplitude.max(0.0);
+    }
+    /// Sets the choke group the voice is in, the group that cuts it off, and how
+    /// it is cut off. Groups of 0 mean none
+    pub fn set_choke(&mut self, choke_group: i32, off_by: i32, off_mode: OffMode){
+        self.choke_group = choke_group;
+        self.off_by = off_by;
+        self.off_mode = off_mode;
+    }
+    /// Returns the choke group of the voice, 0 being none
+    pub fn choke_group(&self)->i32{
+        self.choke_group
+    }
+    /// Cuts the voice off if it is playing and is choked by the given group
+    pub fn choke(&mut self, group: i32){
+        if group == 0 || self.off_by != group || !self.adsr.is_active(){
+            return
+        }
+        match self.off_mode{
+            OffMode::Fast => self.choke_step = 1.0 / (FAST_OFF_TIME * self.sample_rate),
+            // Unlike a note off this also releases one shot voices
+            OffMode::Normal => self.adsr.note_off(),
+        }
+    }
+    /// Sets the tuning of the voice in semitones, and how many semitones the pitch
+    /// moves for each key away from the base note (1.0 by default)
+    pub fn set_tuning(&mut self, semitones: f32, keytrack: f32){
+        self.tuning = semitones;
+        self.pitch_keytrack = keytrack;
+    }
+    /// Places the voice in the stereo field. 
+    /// 
+    /// pan: (-100 to 100) moves the whole voice, width: (-100 to 100) narrows or swaps
+    /// the sample's channels, position: (-100 to 100) moves the narrowed image
+    pub fn set_stereo(&mut self, pan: f32, width: f32, position: f32){
+        let width = 0.01 * fclamp(width, -100.0, 100.0);
+        let narrow = [[0.5 + 0.5 * width, 0.5 - 0.5 * width], [0.5 - 0.5 * width, 0.5 + 0.5 * width]];
+        let (pos_left, pos_right) = balance(position);
+        let (pan_left, pan_right) = balance(pan);
+        self.stereo = [
+            [narrow[0][0] * pos_left * pan_left, narrow[0][1] * pos_left * pan_left],
+            [narrow[1][0] * pos_right * pan_right, narrow[1][1] * pos_right * pan_right],
+        ];
+    }
+    /// Puts back the per-note settings a region may have changed: gain, tuning,
+    /// stereo placement, choke group, envelope delay and hold, and looping behaviour
+    pub fn clear_region_settings(&mut self){
+        self.set_amplitude(1.0);
+        self.set_tuning(0.0, 1.0);
+        self.set_stereo(0.0, 100.0, 0.0);
+        self.set_choke(0, 0, OffMode::Fast);
+        self.set_one_shot(false);
+        self.set_loop_continuous(false);
+        self.adsr.set_delay(0.0);
+        self.adsr.set_hold(0.0);
+    }
+    /// Sets whether the sustain loop keeps going after the note is released
+    pub fn set_loop_continuous(&mut self, loop_continuous: bool){
+        self.loop_continuous = loop_continuous;
+    }
     /// Sets the start point of the sustain loop. If reversed, start_point will serve
     /// as end_point. Values will be clamped within start and end points of the 
     /// sample as a whole.
@@ -258,6 +415,9 @@ impl SamplerVoice{
     pub fn set_sus_points(&mut self, start_point: f32, end_point: f32, length: usize){
         self.set_sus_start(start_point, length);
         self.set_sus_end(end_point, length);
+        // The start is clamped against the previous end point, so it is placed
+        // again now that the end point is up to date
+        self.set_sus_start(start_point, length);
     }
     /// Returns a tuple containing the start and end points (in percent) of the sampler voice
     /// 
@@ -275,6 +435,12 @@ impl SamplerVoice{
         let end_point  = self.sus_end * 100.0 / length as f32;
         (start_point,end_point)
     }
+    /// Returns the tuning in semitones and the keytrack (1.0 follows the keyboard)
+    /// 
+    /// Returns in the format: (semitones, keytrack)
+    pub fn get_tuning(&self)->(f32, f32){
+        (self.tuning, self.pitch_keytrack)
+    }
     /// Returns whether or not the ADSR is active.
     /// 
     /// Useful for voice allocation
@@ -290,10 +456,24 @@ impl SamplerVoice{
         self.fade_time = fclamp(fade_time, 0.0, 0.1);
         self.crossfader.set_values(fade_time*0.5, fade_time*0.5);
     }
+    /// Sets the sample to play from the internal buffer, along with the ratio of its
+    /// sample rate to the output sample rate
+    pub fn set_internal_buffer(&mut self, buffer: Arc<SampleBuffer>, sr_scalar: f32){
+        self.internal_buffer = buffer;
+        self.sr_scalar = sr_scalar;
+    }
+    /// Sets the interpolation kernel used to read between samples
+    pub fn set_interpolation(&mut self, interpolation: Interpolation){
+        self.interpolation = interpolation;
+    }
     /// Sets the sustain mode
     pub fn set_sus_loop_mode(&mut self, mode: SustainModes){
         self.sus_mode = mode;
     }
+    /// Returns the sustain mode
+    pub fn get_sus_loop_mode(&self)->SustainModes{
+        self.sus_mode
+    }
     /// Makes sure there are proper initial values if none have been assigned
     fn check_inits(&mut self, capacity: usize){
         if self.end_point == -1.0{
@@ -307,8 +487,8 @@ impl SamplerVoice{
         }
     }
     /// Handles the logic for the different sustain looping modes
-    fn sus_logic(&mut self, sample: &mut f32, cross_start: f32){
-        if self.adsr.state == AdsrState::Sustain{
+    fn sus_logic(&mut self, gain: &mut f32, cross_start: f32){
+        if self.adsr.state == AdsrState::Sustain || self.loop_continuous{
             if self.sus_mode == SustainModes::LoopWrap{
                  if !self.reversed{
                      if self.phase_offset >= cross_start && self.phase_offset <= cross_start+self.phase_step{
@@ -319,7 +499,7 @@ impl SamplerVoice{
                          self.crossfader.start_fade_in();
                      }
                      if self.phase_offset >= self.sus_start{
-                         *sample *= self.crossfader.get_next_sample();
+                         *gain *= self.crossfader.get_next_sample();
                      }
                  } else{
                      if self.phase_offset <= cross_start && self.phase_offset >= cross_start-self.phase_step{
@@ -330,7 +510,7 @@ impl SamplerVoice{
                          self.crossfader.start_fade_in();
                      }
                      if self.phase_offset <= self.sus_end{
-                         *sample *= self.crossfader.get_next_sample();
+                         *gain *= self.crossfader.get_next_sample();
                      }
                  }
             }else if self.sus_mode == SustainModes::LoopBounce {
@@ -375,6 +555,12 @@ fn fclamp(x: f32, min_val: f32, max_val: f32) -> f32 {
         x
     }
 }
+/// Returns the left and right gains of an equal power pan (-100 to 100), scaled so
+/// both are 1.0 in the centre
+fn balance(pan: f32) -> (f32, f32) {
+    let angle = (0.01 * fclamp(pan, -100.0, 100.0) + 1.0) * std::f32::consts::FRAC_PI_4;
+    (angle.cos() * std::f32::consts::SQRT_2, angle.sin() * std::f32::consts::SQRT_2)
+}
 /// Clamps ints between a min and a max
 fn iclamp(x: i32, min_val: i32, max_val: i32) -> i32 {
     if x < min_val {
@@ -0,0 +1,662 @@
+use crate::sample_buffer::SampleBuffer;
+use crate::sample_pool::SamplePool;
+use crate::sampler_voice::OffMode;
+use crate::sfz::{SfzInstrument, SfzRegion, LoopMode, AmpEnvelope};
+use std::{fmt, fs, io, path::{Path, PathBuf}};
+
+// Generator numbers from the SoundFont 2.01 spec
+const START_OFFSET: u16 = 0;
+const END_OFFSET: u16 = 1;
+const LOOP_START_OFFSET: u16 = 2;
+const LOOP_END_OFFSET: u16 = 3;
+const START_COARSE_OFFSET: u16 = 4;
+const END_COARSE_OFFSET: u16 = 12;
+const PAN: u16 = 17;
+const DELAY_VOL_ENV: u16 = 33;
+const ATTACK_VOL_ENV: u16 = 34;
+const HOLD_VOL_ENV: u16 = 35;
+const DECAY_VOL_ENV: u16 = 36;
+const SUSTAIN_VOL_ENV: u16 = 37;
+const RELEASE_VOL_ENV: u16 = 38;
+const INSTRUMENT: u16 = 41;
+const KEY_RANGE: u16 = 43;
+const VEL_RANGE: u16 = 44;
+const LOOP_START_COARSE_OFFSET: u16 = 45;
+const INITIAL_ATTENUATION: u16 = 48;
+const LOOP_END_COARSE_OFFSET: u16 = 50;
+const COARSE_TUNE: u16 = 51;
+const FINE_TUNE: u16 = 52;
+const SAMPLE_ID: u16 = 53;
+const SAMPLE_MODES: u16 = 54;
+const SCALE_TUNING: u16 = 56;
+const EXCLUSIVE_CLASS: u16 = 57;
+const OVERRIDING_ROOT_KEY: u16 = 58;
+/// Every generator that is mapped onto a region, the rest are reported as unsupported
+const SUPPORTED_GENERATORS: [u16; 26] = [
+    START_OFFSET, END_OFFSET, LOOP_START_OFFSET, LOOP_END_OFFSET, START_COARSE_OFFSET, END_COARSE_OFFSET,
+    PAN, DELAY_VOL_ENV, ATTACK_VOL_ENV, HOLD_VOL_ENV, DECAY_VOL_ENV, SUSTAIN_VOL_ENV, RELEASE_VOL_ENV,
+    INSTRUMENT, KEY_RANGE, VEL_RANGE, LOOP_START_COARSE_OFFSET, INITIAL_ATTENUATION, LOOP_END_COARSE_OFFSET,
+    COARSE_TUNE, FINE_TUNE, SAMPLE_ID, SAMPLE_MODES, SCALE_TUNING, EXCLUSIVE_CLASS, OVERRIDING_ROOT_KEY,
+];
+/// Set in a sample header's type when the sample lives in a sound card's ROM
+const ROM_SAMPLE: u16 = 0x8000;
+
+/// The generators of one preset or instrument zone, as (generator, amount) pairs
+#[derive(Clone, Default, Debug)]
+struct Zone{
+    generators: Vec<(u16, u16)>,
+}
+
+impl Zone{
+    fn get(&self, generator: u16)->Option<u16>{
+        self.generators.iter().rev().find(|(gen, _)| *gen == generator).map(|(_, amount)| *amount)
+    }
+    /// Returns whether the zone's last generator is the given one, which is how
+    /// local zones are told apart from a global zone
+    fn ends_with(&self, generator: u16)->bool{
+        self.generators.last().is_some_and(|(gen, _)| *gen == generator)
+    }
+}
+
+/// The zones that make up a preset or instrument.
+/// The first zone is global when it doesn't end in an instrument or sample
+#[derive(Clone, Debug)]
+struct Header{
+    name: String,
+    zones: Vec<Zone>,
+    /// How many modulators the zones have, none of which are supported
+    modulators: usize,
+}
+
+impl Header{
+    /// Splits the zones into the optional global zone and the local ones
+    fn split_zones(&self, terminal: u16)->(Option<&Zone>, &[Zone]){
+        match self.zones.first(){
+            Some(first) if !first.ends_with(terminal) => (Some(first), &self.zones[1..]),
+            _ => (None, &self.zones[..]),
+        }
+    }
+}
+
+/// Picks out a preset by its MIDI bank and program, the way a keyboard would
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct PresetNumber{
+    pub bank: u16,
+    pub program: u16,
+}
+
+/// One of a bank's presets, which plays its zones' instruments
+struct Preset{
+    number: PresetNumber,
+    header: Header,
+}
+
+/// Where a sample sits in the bank's sample data
+#[derive(Clone, Debug)]
+struct SampleHeader{
+    name: String,
+    start: usize,
+    end: usize,
+    loop_start: usize,
+    loop_end: usize,
+    sample_rate: f32,
+    original_pitch: u8,
+    pitch_correction: i8,
+    sample_type: u16,
+}
+
+/// A parsed SoundFont bank, from which the regions of any one preset can be built
+pub struct SoundFont{
+    presets: Vec<Preset>,
+    instruments: Vec<Header>,
+    samples: Vec<SampleHeader>,
+    sample_data: Vec<f32>,
+}
+
+/// Why a SoundFont couldn't be loaded
+#[derive(Debug)]
+pub enum Sf2Error{
+    Unreadable{ path: PathBuf, error: io::Error },
+    /// The file isn't a SoundFont or one of its chunks is malformed
+    Invalid(&'static str),
+    /// The bank has no preset with the bank and program asked for
+    NoPreset{ preset: PresetNumber },
+    /// None of the preset's zones could be played. The warnings say why
+    NoRegions{ warnings: Vec<Sf2Warning> },
+}
+
+impl fmt::Display for Sf2Error{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>)->fmt::Result{
+        match self{
+            Sf2Error::Unreadable{ path, error } => write!(f, "Couldn't read {}: {}", path.display(), error),
+            Sf2Error::Invalid(reason) => write!(f, "Not a valid SoundFont: {}", reason),
+            Sf2Error::NoPreset{ preset } => write!(f, "There is no preset with bank {} and program {}", preset.bank, preset.program),
+            Sf2Error::NoRegions{ warnings } => write!(f, "The preset has no playable zones ({} warnings)", warnings.len()),
+        }
+    }
+}
+
+impl Sf2Error{
+    /// Returns the warnings that explain why nothing could be played, if there are any
+    pub fn warnings(&self)->&[Sf2Warning]{
+        match self{
+            Sf2Error::NoRegions{ warnings } => warnings,
+            _ => &[],
+        }
+    }
+}
+
+impl std::error::Error for Sf2Error{}
+
+/// Something in a SoundFont that was skipped while loading a preset
+#[derive(Clone, Debug, PartialEq)]
+pub enum Sf2Warning{
+    UnsupportedGenerator{ generator: u16 },
+    /// The sample is stored in a sound card's ROM rather than in the file
+    RomSample{ name: String },
+    /// A zone points at a sample header or sample data that doesn't exist
+    MissingSample{ sample: u16 },
+    /// A preset zone points at an instrument that doesn't exist
+    MissingInstrument{ instrument: u16 },
+    /// The preset or instrument with this name has modulators, which are ignored
+    UnsupportedModulators{ name: String },
+}
+
+impl fmt::Display for Sf2Warning{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>)->fmt::Result{
+        match self{
+            Sf2Warning::UnsupportedGenerator{ generator } => write!(f, "Generator {} isn't supported", generator),
+            Sf2Warning::RomSample{ name } => write!(f, "Sample {} is stored in ROM and can't be played", name),
+            Sf2Warning::MissingSample{ sample } => write!(f, "Sample {} doesn't exist", sample),
+            Sf2Warning::MissingInstrument{ instrument } => write!(f, "Instrument {} doesn't exist", instrument),
+            Sf2Warning::UnsupportedModulators{ name } => write!(f, "{} uses modulators, which aren't supported", name),
+        }
+    }
+}
+
+/// A RIFF chunk's id and contents
+struct Chunk<'a>{
+    id: [u8; 4],
+    data: &'a [u8],
+}
+
+fn read_u16(data: &[u8], at: usize)->u16{
+    u16::from_le_bytes([data[at], data[at + 1]])
+}
+fn read_u32(data: &[u8], at: usize)->u32{
+    u32::from_le_bytes([data[at], data[at + 1], data[at + 2], data[at + 3]])
+}
+/// Reads a fixed length name, which is zero padded
+fn read_name(data: &[u8])->String{
+    let end = data.iter().position(|byte| *byte == 0).unwrap_or(data.len());
+    String::from_utf8_lossy(&data[..end]).trim().to_string()
+}
+
+/// Splits a run of RIFF chunks apart
+fn read_chunks(mut data: &[u8])->Result<Vec<Chunk<'_>>, Sf2Error>{
+    let mut chunks = Vec::new();
+    while data.len() >= 8{
+        let id = [data[0], data[1], data[2], data[3]];
+        let size = read_u32(data, 4) as usize;
+        let body = data.get(8..8 + size).ok_or(Sf2Error::Invalid("a chunk runs past the end of the file"))?;
+        chunks.push(Chunk{ id, data: body });
+        // Chunks are padded to an even length
+        let next = (8 + size + 1) & !1;
+        data = data.get(next..).unwrap_or(&[]);
+    }
+    Ok(chunks)
+}
+/// Returns the sub-chunks of the LIST chunk of the given type
+fn list<'a>(chunks: &[Chunk<'a>], list_type: &[u8; 4])->Option<Vec<Chunk<'a>>>{
+    chunks.iter()
+        .filter(|chunk| &chunk.id == b"LIST" && chunk.data.len() >= 4 && &chunk.data[0..4] == list_type)
+        .find_map(|chunk| read_chunks(&chunk.data[4..]).ok())
+}
+/// Returns the records of a chunk of fixed size records
+fn records<'a>(chunks: &[Chunk<'a>], id: &[u8; 4], size: usize)->Result<Vec<&'a [u8]>, Sf2Error>{
+    let chunk = chunks.iter().find(|chunk| &chunk.id == id).ok_or(Sf2Error::Invalid("a preset or instrument chunk is missing"))?;
+    if chunk.data.len() % size != 0{
+        return Err(Sf2Error::Invalid("a chunk's size doesn't match its records"))
+    }
+    Ok(chunk.data.chunks_exact(size).collect())
+}
+/// Groups the generators of a pbag/pgen or ibag/igen pair into zones.
+/// `bag_ranges` are the first bag of each header, ending with the terminal record
+fn read_zones(bag_ranges: &[usize], bags: &[&[u8]], generators: &[&[u8]])->Result<Vec<Vec<Zone>>, Sf2Error>{
+    let mut headers = Vec::new();
+    for range in bag_ranges.windows(2){
+        if range[0] > range[1] || range[1] >= bags.len(){
+            return Err(Sf2Error::Invalid("a zone index is out of range"))
+        }
+        let mut zones = Vec::new();
+        for bag in range[0]..range[1]{
+            let first = read_u16(bags[bag], 0) as usize;
+            let last = read_u16(bags[bag + 1], 0) as usize;
+            let gens = generators.get(first..last).ok_or(Sf2Error::Invalid("a generator index is out of range"))?;
+            zones.push(Zone{
+                generators: gens.iter().map(|gen| (read_u16(gen, 0), read_u16(gen, 2))).collect(),
+            });
+        }
+        headers.push(zones);
+    }
+    Ok(headers)
+}
+
+/// Counts the modulators of each preset or instrument from a pbag or ibag chunk, whose
+/// records hold the index of their first modulator. Call after `read_zones`, which
+/// checks the ranges
+fn count_modulators(bag_ranges: &[usize], bags: &[&[u8]])->Vec<usize>{
+    bag_ranges.windows(2)
+        .map(|range| (read_u16(bags[range[1]], 2) as usize).saturating_sub(read_u16(bags[range[0]], 2) as usize))
+        .collect()
+}
+
+impl SoundFont{
+    /// Parses the RIFF structure of a bank
+    pub fn parse(data: &[u8])->Result<Self, Sf2Error>{
+        if data.len() < 12 || &data[0..4] != b"RIFF" || &data[8..12] != b"sfbk"{
+            return Err(Sf2Error::Invalid("the file doesn't start with a RIFF sfbk header"))
+        }
+        let riff_end = (8 + read_u32(data, 4) as usize).min(data.len());
+        let chunks = read_chunks(&data[12..riff_end])?;
+        let sdta = list(&chunks, b"sdta").ok_or(Sf2Error::Invalid("there is no sample data"))?;
+        let pdta = list(&chunks, b"pdta").ok_or(Sf2Error::Invalid("there are no presets"))?;
+
+        // 16 bit samples, with an optional chunk holding the low byte of 24 bit ones
+        let smpl = sdta.iter().find(|chunk| &chunk.id == b"smpl").map(|chunk| chunk.data).unwrap_or(&[]);
+        let sm24 = sdta.iter().find(|chunk| &chunk.id == b"sm24").map(|chunk| chunk.data)
+            .filter(|sm24| sm24.len() >= smpl.len() / 2);
+        let sample_data = smpl.chunks_exact(2).enumerate().map(|(idx, bytes)| {
+            let high = i16::from_le_bytes([bytes[0], bytes[1]]) as i32;
+            match sm24{
+                Some(sm24) => ((high << 8) | sm24[idx] as i32) as f32 / 8388608.0,
+                None => high as f32 / 32768.0,
+            }
+        }).collect();
+
+        let phdr = records(&pdta, b"phdr", 38)?;
+        let pbag = records(&pdta, b"pbag", 4)?;
+        let pgen = records(&pdta, b"pgen", 4)?;
+        let inst = records(&pdta, b"inst", 22)?;
+        let ibag = records(&pdta, b"ibag", 4)?;
+        let igen = records(&pdta, b"igen", 4)?;
+        let shdr = records(&pdta, b"shdr", 46)?;
+        if phdr.len() < 2 || inst.len() < 2{
+            return Err(Sf2Error::Invalid("there are no presets or instruments"))
+        }
+
+        let preset_bags: Vec<usize> = phdr.iter().map(|record| read_u16(record, 24) as usize).collect();
+        let preset_zones = read_zones(&preset_bags, &pbag, &pgen)?;
+        let preset_modulators = count_modulators(&preset_bags, &pbag);
+        let mut presets: Vec<Preset> = phdr.iter().zip(preset_zones).zip(preset_modulators).map(|((record, zones), modulators)| Preset{
+            number: PresetNumber{
+                program: read_u16(record, 20),
+                bank: read_u16(record, 22),
+            },
+            header: Header{ name: read_name(&record[0..20]), zones, modulators },
+        }).collect();
+        // Presets can be stored in any order, they're listed the way a keyboard would
+        presets.sort_by_key(|preset| (preset.number.bank, preset.number.program));
+
+        let instrument_bags: Vec<usize> = inst.iter().map(|record| read_u16(record, 20) as usize).collect();
+        let instrument_zones = read_zones(&instrument_bags, &ibag, &igen)?;
+        let instrument_modulators = count_modulators(&instrument_bags, &ibag);
+        let instruments = inst.iter().zip(instrument_zones).zip(instrument_modulators)
+            .map(|((record, zones), modulators)| Header{ name: read_name(&record[0..20]), zones, modulators })
+            .collect();
+
+        // The last sample header is a terminal record
+        let samples = shdr[..shdr.len().saturating_sub(1)].iter().map(|record| SampleHeader{
+            name: read_name(&record[0..20]),
+            start: read_u32(record, 20) as usize,
+            end: read_u32(record, 24) as usize,
+            loop_start: read_u32(record, 28) as usize,
+            loop_end: read_u32(record, 32) as usize,
+            sample_rate: read_u32(record, 36) as f32,
+            original_pitch: record[40],
+            pitch_correction: record[41] as i8,
+            sample_type: read_u16(record, 44),
+        }).collect();
+
+        Ok(SoundFont{
+            presets,
+            instruments,
+            samples,
+            sample_data,
+        })
+    }
+    /// Builds the regions of the preset with the given bank and program, named after it.
+    /// `path` keys the preset's samples in the pool
+    pub fn instrument(&self, preset: PresetNumber, path: &Path)->Result<(SfzInstrument, Vec<Sf2Warning>), Sf2Error>{
+        let Preset{ header, .. } = self.presets.iter().find(|found| found.number == preset)
+            .ok_or(Sf2Error::NoPreset{ preset })?;
+        let mut pool = SamplePool::new();
+        let mut regions = Vec::new();
+        let mut warnings = Vec::new();
+        if header.modulators > 0{
+            warnings.push(Sf2Warning::UnsupportedModulators{ name: header.name.clone() });
+        }
+        let (preset_global, preset_zones) = header.split_zones(INSTRUMENT);
+        for preset_zone in preset_zones.iter(){
+            let Some(instrument_id) = preset_zone.get(INSTRUMENT) else {
+                continue
+            };
+            let Some(instrument) = self.instruments.get(instrument_id as usize) else {
+                warnings.push(Sf2Warning::MissingInstrument{ instrument: instrument_id });
+                continue
+            };
+            let warning = Sf2Warning::UnsupportedModulators{ name: instrument.name.clone() };
+            if instrument.modulators > 0 && !warnings.contains(&warning){
+                warnings.push(warning);
+            }
+            let (instrument_global, instrument_zones) = instrument.split_zones(SAMPLE_ID);
+            for instrument_zone in instrument_zones.iter(){
+                let zones = ZoneStack{
+                    preset_global,
+                    preset_local: preset_zone,
+                    instrument_global,
+                    instrument_local: instrument_zone,
+                };
+                for zone in [preset_global, Some(preset_zone), instrument_global, Some(instrument_zone)].into_iter().flatten(){
+                    for (generator, _) in zone.generators.iter(){
+                        let warning = Sf2Warning::UnsupportedGenerator{ generator: *generator };
+                        if !SUPPORTED_GENERATORS.contains(generator) && !warnings.contains(&warning){
+                            warnings.push(warning);
+                        }
+                    }
+                }
+                match self.region(&zones, path, &mut pool){
+                    Ok(Some(region)) => regions.push(region),
+                    Ok(None) => (),
+                    Err(warning) => {
+                        if !warnings.contains(&warning){
+                            warnings.push(warning);
+                        }
+                    },
+                }
+            }
+        }
+        if regions.is_empty(){
+            return Err(Sf2Error::NoRegions{ warnings })
+        }
+        let mut instrument = SfzInstrument::new(regions, pool);
+        instrument.name = Some(header.name.clone());
+        Ok((instrument, warnings))
+    }
+    /// Maps one instrument zone, as played through one preset zone, onto a region.
+    /// Returns None if the preset's key or velocity range excludes the zone entirely
+    fn region(&self, zones: &ZoneStack, path: &Path, pool: &mut SamplePool)->Result<Option<SfzRegion>, Sf2Warning>{
+        let sample_id = zones.instrument_local.get(SAMPLE_ID).unwrap_or_default();
+        let header = self.samples.get(sample_id as usize).ok_or(Sf2Warning::MissingSample{ sample: sample_id })?;
+        if header.sample_type & ROM_SAMPLE != 0{
+            return Err(Sf2Warning::RomSample{ name: header.name.clone() })
+        }
+        let (lokey, hikey) = zones.range(KEY_RANGE);
+        let (lovel, hivel) = zones.range(VEL_RANGE);
+        if lokey > hikey || lovel > hivel{
+            return Ok(None)
+        }
+        let key = PathBuf::from(format!("{}#{}", path.display(), sample_id));
+        let sample = match pool.get(&key){
+            Some(sample) => sample,
+            None => {
+                let data = self.sample_data.get(header.start..header.end)
+                    .filter(|data| !data.is_empty())
+                    .ok_or(Sf2Warning::MissingSample{ sample: sample_id })?;
+                pool.insert(&key, SampleBuffer::from_interleaved(data, 1), header.sample_rate, None)
+            },
+        };
+        let length = header.end - header.start;
+        let mut region = SfzRegion::new(sample);
+        region.lokey = lokey;
+        region.hikey = hikey;
+        region.lovel = lovel as f32;
+        region.hivel = hivel as f32;
+
+        let root = zones.instrument(OVERRIDING_ROOT_KEY)
+            .filter(|key| (0..=127).contains(key))
+            .unwrap_or(if header.original_pitch <= 127 {header.original_pitch as i32} else {60});
+        region.pitch_keycenter = Some(root as u8);
+        region.transpose = zones.additive(COARSE_TUNE, 0);
+        region.tune = (header.pitch_correction as i32 + zones.additive(FINE_TUNE, 0)) as f32;
+        region.pitch_keytrack = zones.additive(SCALE_TUNING, 100) as f32;
+        // Attenuation is in centibels
+        region.volume = -zones.additive(INITIAL_ATTENUATION, 0).max(0) as f32 / 10.0;
+        // Pan runs from -500 to 500 tenths of a percent
+        region.pan = (zones.additive(PAN, 0) as f32 / 5.0).clamp(-100.0, 100.0);
+
+        // Address offsets only exist at the instrument level and are relative to the sample
+        let offset = zones.address(START_OFFSET, START_COARSE_OFFSET);
+        if offset > 0{
+            region.offset = Some((offset as u32).min(length as u32 - 1));
+        }
+        let end = length as i32 - 1 + zones.address(END_OFFSET, END_COARSE_OFFSET);
+        if end < length as i32 - 1{
+            region.end = Some(end.max(0) as u32);
+        }
+        region.loop_mode = Some(match zones.instrument(SAMPLE_MODES).unwrap_or(0) & 3{
+            1 => LoopMode::LoopContinuous,
+            3 => LoopMode::LoopSustain,
+            _ => LoopMode::NoLoop,
+        });
+        if region.loop_mode != Some(LoopMode::NoLoop){
+            // The header's loop end is the first frame after the loop
+            let loop_start = header.loop_start as i32 - header.start as i32 + zones.address(LOOP_START_OFFSET, LOOP_START_COARSE_OFFSET);
+            let loop_end = header.loop_end as i32 - header.start as i32 - 1 + zones.address(LOOP_END_OFFSET, LOOP_END_COARSE_OFFSET);
+            region.loop_start = Some(loop_start.clamp(0, length as i32 - 1) as u32);
+            region.loop_end = Some(loop_end.clamp(0, length as i32 - 1) as u32);
+        }
+
+        region.ampeg = AmpEnvelope{
+            delay: zones.timecents(DELAY_VOL_ENV),
+            attack: zones.timecents(ATTACK_VOL_ENV),
+            hold: zones.timecents(HOLD_VOL_ENV),
+            decay: zones.timecents(DECAY_VOL_ENV),
+            // Sustain is an attenuation in centibels, the region wants a percentage
+            sustain: zones.has(SUSTAIN_VOL_ENV).then(|| {
+                let centibels = zones.additive(SUSTAIN_VOL_ENV, 0).clamp(0, 1440) as f32;
+                100.0 * 10.0f32.powf(-centibels / 200.0)
+            }),
+            release: zones.timecents(RELEASE_VOL_ENV),
+        };
+
+        // Zones of an exclusive class cut each other off, like a hi-hat
+        let exclusive_class = zones.instrument(EXCLUSIVE_CLASS).unwrap_or(0);
+        if exclusive_class > 0{
+            region.choke_group = exclusive_class;
+            region.off_by = exclusive_class;
+            region.off_mode = OffMode::Fast;
+        }
+        Ok(Some(region))
+    }
+}
+
+/// The four zones that apply to one region. Instrument generators set values,
+/// with the local zone overriding the global one, and preset generators add to them
+struct ZoneStack<'a>{
+    preset_global: Option<&'a Zone>,
+    preset_local: &'a Zone,
+    instrument_global: Option<&'a Zone>,
+    instrument_local: &'a Zone,
+}
+
+impl ZoneStack<'_>{
+    fn preset(&self, generator: u16)->Option<i32>{
+        self.preset_local.get(generator).or_else(|| self.preset_global?.get(generator)).map(|amount| amount as i16 as i32)
+    }
+    fn instrument(&self, generator: u16)->Option<i32>{
+        self.instrument_local.get(generator).or_else(|| self.instrument_global?.get(generator)).map(|amount| amount as i16 as i32)
+    }
+    fn has(&self, generator: u16)->bool{
+        self.preset(generator).is_some() || self.instrument(generator).is_some()
+    }
+    /// Returns the instrument's value, or the default, offset by the preset's
+    fn additive(&self, generator: u16, default: i32)->i32{
+        self.instrument(generator).unwrap_or(default) + self.preset(generator).unwrap_or(0)
+    }
+    /// Returns an envelope time in seconds, if any zone sets it
+    fn timecents(&self, generator: u16)->Option<f32>{
+        self.has(generator).then(|| {
+            let timecents = self.additive(generator, -12000).clamp(-12000, 8000);
+            2.0f32.powf(timecents as f32 / 1200.0)
+        })
+    }
+    /// Returns a sample address offset, combining its fine and coarse generators
+    fn address(&self, fine: u16, coarse: u16)->i32{
+        self.instrument(fine).unwrap_or(0) + 32768 * self.instrument(coarse).unwrap_or(0)
+    }
+    /// Returns a key or velocity range. Preset ranges narrow the instrument's
+    fn range(&self, generator: u16)->(u8, u8){
+        let bounds = |amount: Option<u16>| amount.map_or((0, 127), |amount| ((amount & 0xff) as u8, (amount >> 8) as u8));
+        let instrument = bounds(self.instrument_local.get(generator).or_else(|| self.instrument_global?.get(generator)));
+        let preset = bounds(self.preset_local.get(generator).or_else(|| self.preset_global?.get(generator)));
+        (instrument.0.max(preset.0), instrument.1.min(preset.1))
+    }
+}
+
+/// Loads one preset of a SoundFont as an instrument for the Sfz mode
+pub fn load_sf2(path: &Path, preset: PresetNumber)->Result<(SfzInstrument, Vec<Sf2Warning>), Sf2Error>{
+    let data = fs::read(path).map_err(|error| Sf2Error::Unreadable{ path: path.to_path_buf(), error })?;
+    SoundFont::parse(&data)?.instrument(preset, path)
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    fn chunk(id: &[u8; 4], data: &[u8])->Vec<u8>{
+        let mut out = id.to_vec();
+        out.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        out.extend_from_slice(data);
+        if data.len() % 2 == 1{
+            out.push(0);
+        }
+        out
+    }
+    fn list_chunk(list_type: &[u8; 4], chunks: &[Vec<u8>])->Vec<u8>{
+        let mut data = list_type.to_vec();
+        for sub in chunks.iter(){
+            data.extend_from_slice(sub);
+        }
+        chunk(b"LIST", &data)
+    }
+    fn name(name: &str)->Vec<u8>{
+        let mut out = name.as_bytes().to_vec();
+        out.resize(20, 0);
+        out
+    }
+    fn generators(gens: &[(u16, u16)])->Vec<u8>{
+        gens.iter().flat_map(|(gen, amount)| [gen.to_le_bytes(), amount.to_le_bytes()].concat()).collect()
+    }
+    /// Bag records from the index of each bag's first generator and first modulator
+    fn bags(first: &[(u16, u16)])->Vec<u8>{
+        first.iter().flat_map(|(gen, modulator)| [gen.to_le_bytes(), modulator.to_le_bytes()].concat()).collect()
+    }
+
+    /// Builds a bank with two presets playing one instrument of two zones. With
+    /// `modulators`, the "Second" preset and the instrument each have a modulator
+    fn test_bank(modulators: bool)->Vec<u8>{
+        let mut smpl = Vec::new();
+        for frame in 0..300{
+            let value: i16 = if frame < 100 {16384} else {-8192};
+            smpl.extend_from_slice(&value.to_le_bytes());
+        }
+        let mut phdr = Vec::new();
+        // Stored out of order, "Second" is program 1
+        for (preset_name, program, bag) in [("Second", 1u16, 0u16), ("First", 0, 1), ("EOP", 0, 2)]{
+            phdr.extend(name(preset_name));
+            phdr.extend_from_slice(&program.to_le_bytes());
+            phdr.extend_from_slice(&0u16.to_le_bytes());
+            phdr.extend_from_slice(&bag.to_le_bytes());
+            phdr.extend_from_slice(&[0; 12]);
+        }
+        let pgen = generators(&[(KEY_RANGE, 10 << 8), (INSTRUMENT, 0), (INITIAL_ATTENUATION, 60), (INSTRUMENT, 0), (0, 0)]);
+        let mut inst = name("Inst");
+        inst.extend_from_slice(&0u16.to_le_bytes());
+        inst.extend(name("EOI"));
+        inst.extend_from_slice(&3u16.to_le_bytes());
+        let igen = generators(&[
+            // Global zone, an attack of -1200 timecents is half a second
+            (ATTACK_VOL_ENV, (-1200i16) as u16),
+            (KEY_RANGE, 59 << 8), (SAMPLE_MODES, 1), (SAMPLE_ID, 0),
+            (KEY_RANGE, 60 | 127 << 8), (VEL_RANGE, 100 << 8), (OVERRIDING_ROOT_KEY, 74), (SAMPLE_ID, 1),
+            (0, 0),
+        ]);
+        let mut shdr = Vec::new();
+        for (sample_name, start, end, loop_start, loop_end, rate, pitch) in [
+            ("Low", 0u32, 100u32, 10u32, 90u32, 22050u32, 60u8),
+            ("High", 100, 300, 120, 280, 44100, 72),
+            ("EOS", 0, 0, 0, 0, 0, 0),
+        ]{
+            shdr.extend(name(sample_name));
+            for value in [start, end, loop_start, loop_end, rate]{
+                shdr.extend_from_slice(&value.to_le_bytes());
+            }
+            shdr.extend_from_slice(&[pitch, 0, 0, 0, 1, 0]);
+        }
+        let body = [
+            b"sfbk".to_vec(),
+            list_chunk(b"INFO", &[chunk(b"ifil", &[2, 0, 1, 0])]),
+            list_chunk(b"sdta", &[chunk(b"smpl", &smpl)]),
+            list_chunk(b"pdta", &[
+                chunk(b"phdr", &phdr),
+                chunk(b"pbag", &bags(&[(0, 0), (2, modulators as u16), (4, modulators as u16)])),
+                chunk(b"pmod", &vec![0; 10 + 10 * modulators as usize]),
+                chunk(b"pgen", &pgen),
+                chunk(b"inst", &inst),
+                chunk(b"ibag", &bags(&[(0, 0), (1, 0), (4, 0), (8, modulators as u16)])),
+                chunk(b"imod", &vec![0; 10 + 10 * modulators as usize]),
+                chunk(b"igen", &igen),
+                chunk(b"shdr", &shdr),
+            ]),
+        ].concat();
+        chunk(b"RIFF", &body)
+    }
+
+    #[test]
+    fn test_sf2_import(){
+        let bank = SoundFont::parse(&test_bank(false)).unwrap();
+        let programs: Vec<u16> = bank.presets.iter().map(|preset| preset.number.program).collect();
+        assert_eq!(programs, [0, 1]);
+
+        let (instrument, warnings) = bank.instrument(PresetNumber{ bank: 0, program: 0 }, Path::new("test.sf2")).unwrap();
+        assert!(warnings.is_empty());
+        assert_eq!(instrument.name.as_deref(), Some("First"));
+        assert_eq!(instrument.regions.len(), 2);
+        let low = &instrument.regions[0];
+        assert_eq!((low.lokey, low.hikey), (0, 59));
+        assert_eq!(low.pitch_keycenter, Some(60));
+        assert_eq!(low.loop_mode, Some(LoopMode::LoopContinuous));
+        assert_eq!((low.loop_start, low.loop_end), (Some(10), Some(89)));
+        assert!((low.ampeg.attack.unwrap() - 0.5).abs() < 1e-4);
+        assert_eq!(low.ampeg.release, None);
+        assert!((low.volume + 6.0).abs() < 1e-4);
+        assert_eq!(low.sample.sample_rate, 22050.0);
+        assert_eq!(low.sample.buffer.capacity(), 100);
+        assert_eq!(low.sample.buffer.channel(0).get(0), 0.5);
+        let high = &instrument.regions[1];
+        assert_eq!((high.lokey, high.hikey, high.hivel), (60, 127, 100.0));
+        assert_eq!(high.pitch_keycenter, Some(74));
+        assert_eq!(high.loop_mode, Some(LoopMode::NoLoop));
+        assert_eq!(high.sample.buffer.capacity(), 200);
+        assert_eq!(high.sample.buffer.channel(0).get(0), -0.25);
+
+        // The second preset's key range narrows the instrument's
+        let (instrument, _) = bank.instrument(PresetNumber{ bank: 0, program: 1 }, Path::new("test.sf2")).unwrap();
+        assert_eq!(instrument.name.as_deref(), Some("Second"));
+        assert_eq!(instrument.regions.len(), 1);
+        assert_eq!((instrument.regions[0].lokey, instrument.regions[0].hikey), (0, 10));
+
+        // Modulators are ignored, but each preset and instrument using them is reported once
+        let modulated = SoundFont::parse(&test_bank(true)).unwrap();
+        let modulators = |name: &str| Sf2Warning::UnsupportedModulators{ name: name.to_string() };
+        let (_, warnings) = modulated.instrument(PresetNumber{ bank: 0, program: 0 }, Path::new("test.sf2")).unwrap();
+        assert_eq!(warnings, vec![modulators("Inst")]);
+        let (_, warnings) = modulated.instrument(PresetNumber{ bank: 0, program: 1 }, Path::new("test.sf2")).unwrap();
+        assert_eq!(warnings, vec![modulators("Second"), modulators("Inst")]);
+
+        let missing = PresetNumber{ bank: 1, program: 0 };
+        assert!(matches!(bank.instrument(missing, Path::new("test.sf2")), Err(Sf2Error::NoPreset{ preset }) if preset == missing));
+        assert!(matches!(SoundFont::parse(b"RIFF\x04\x00\x00\x00WAVE"), Err(Sf2Error::Invalid(_))));
+    }
+}
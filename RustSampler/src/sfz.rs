@@ -0,0 +1,1206 @@
+use crate::sample_pool::{SamplePool, PooledSample};
+use crate::decoder::LoadError;
+use crate::sampler_voice::OffMode;
+use std::{collections::HashMap, fmt, fs, io, path::{Path, PathBuf}};
+
+/// A region of an SFZ instrument with its sample already decoded
+#[derive(Clone)]
+pub struct SfzRegion{
+    pub lokey: u8,
+    pub hikey: u8,
+    pub lovel: f32,
+    pub hivel: f32,
+    pub pitch_keycenter: Option<u8>,
+    /// First frame to play
+    pub offset: Option<u32>,
+    /// Last frame to play
+    pub end: Option<u32>,
+    pub loop_mode: Option<LoopMode>,
+    /// First frame of the loop
+    pub loop_start: Option<u32>,
+    /// Last frame of the loop
+    pub loop_end: Option<u32>,
+    /// Index of the `<group>` the region belongs to, used for round robins
+    pub group: usize,
+    /// Number of steps in the group's round robin
+    pub seq_length: u32,
+    /// Step of the round robin this region plays on, counting from 1
+    pub seq_position: u32,
+    /// The region plays when the note's random value is in lorand..hirand
+    pub lorand: f32,
+    pub hirand: f32,
+    /// Range of keys used as keyswitches by the instrument
+    pub sw_lokey: Option<u8>,
+    pub sw_hikey: Option<u8>,
+    /// The region only plays when this was the last keyswitch pressed
+    pub sw_last: Option<u8>,
+    /// The region only plays while this key is held down
+    pub sw_down: Option<u8>,
+    /// The region only plays while this key is up
+    pub sw_up: Option<u8>,
+    /// Keyswitch that is active before any has been pressed
+    pub sw_default: Option<u8>,
+    /// Name of the region's articulation, shown in the editor
+    pub sw_label: Option<String>,
+    /// Whether the region starts on note on or note off
+    pub trigger: Trigger,
+    /// How quickly release triggered regions get quieter the longer the note was
+    /// held, in dB per second
+    pub rt_decay: f32,
+    /// The region only plays while these CCs are in range (`locc`/`hicc`)
+    pub cc_conditions: Vec<CcRange>,
+    /// The region is started by these CCs moving into range rather than by notes
+    /// (`on_locc`/`on_hicc`)
+    pub cc_triggers: Vec<CcRange>,
+    /// Choke group set by the `group` opcode, 0 being none. Not to be confused
+    /// with `group`, the index of the region's `<group>` header
+    pub choke_group: i32,
+    /// The region is cut off when a region in this choke group starts
+    pub off_by: i32,
+    pub off_mode: OffMode,
+    /// Gain in dB
+    pub volume: f32,
+    /// Gain in percent
+    pub amplitude: f32,
+    /// Stereo placement, each from -100 to 100
+    pub pan: f32,
+    pub width: f32,
+    pub position: f32,
+    /// Fine tuning in cents
+    pub tune: f32,
+    /// Transposition in semitones
+    pub transpose: i32,
+    /// How many cents the pitch moves for each key
+    pub pitch_keytrack: f32,
+    /// How much velocity affects the gain, in percent
+    pub amp_veltrack: f32,
+    /// Points of the velocity to gain curve as (velocity 0-127, gain 0-1), sorted by velocity
+    pub amp_velcurve: Vec<(f32, f32)>,
+    pub ampeg: AmpEnvelope,
+    /// Velocity (0-127) and key crossfades. The region fades in across the `xfin`
+    /// range and out across the `xfout` range
+    pub xfin_vel: (f32, f32),
+    pub xfout_vel: (f32, f32),
+    pub xfin_key: (f32, f32),
+    pub xfout_key: (f32, f32),
+    pub xf_velcurve: CrossfadeCurve,
+    pub xf_keycurve: CrossfadeCurve,
+    pub sample: PooledSample,
+}
+
+/// What starts a region, following the SFZ `trigger` opcode
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Trigger{
+    Attack, // Plays on note on. `first` and `legato` are treated the same way
+    Release, // Plays on note off
+    ReleaseKey, // Plays on note off, there is no sustain pedal to wait for
+}
+
+/// The `ampeg_` opcodes of a region. Times are in seconds and sustain is in percent.
+/// Anything unset falls back to the plugin's envelope
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct AmpEnvelope{
+    pub delay: Option<f32>,
+    pub attack: Option<f32>,
+    pub hold: Option<f32>,
+    pub decay: Option<f32>,
+    pub sustain: Option<f32>,
+    pub release: Option<f32>,
+}
+
+/// The shape of a crossfade, following the SFZ `xf_velcurve` and `xf_keycurve` opcodes
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum CrossfadeCurve{
+    Power, // Keeps the summed power of two layers constant
+    Gain, // Keeps the summed amplitude of two layers constant
+}
+
+impl CrossfadeCurve{
+    /// Returns the gain at a position (0-1) through a fade in
+    fn gain(&self, position: f32)->f32{
+        let position = position.clamp(0.0, 1.0);
+        match self{
+            CrossfadeCurve::Power => position.sqrt(),
+            CrossfadeCurve::Gain => position,
+        }
+    }
+    /// Returns the gain for a value fading in across `lo..hi`
+    pub fn fade_in(&self, value: f32, (lo, hi): (f32, f32))->f32{
+        if value <= lo{
+            return if value >= hi {1.0} else {0.0}
+        }
+        if value >= hi{
+            return 1.0
+        }
+        self.gain((value - lo) / (hi - lo))
+    }
+    /// Returns the gain for a value fading out across `lo..hi`
+    pub fn fade_out(&self, value: f32, (lo, hi): (f32, f32))->f32{
+        if value >= hi{
+            return if value <= lo {1.0} else {0.0}
+        }
+        if value <= lo{
+            return 1.0
+        }
+        self.gain((hi - value) / (hi - lo))
+    }
+}
+
+/// A range of values of a MIDI CC, from 0 to 127 like in the SFZ file
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct CcRange{
+    pub cc: u8,
+    pub lo: f32,
+    pub hi: f32,
+}
+
+impl CcRange{
+    /// Returns whether a CC value (0-1) is within the range
+    pub fn contains(&self, value: f32)->bool{
+        value*127.0 >= self.lo && value*127.0 <= self.hi
+    }
+}
+
+/// How a region loops, following the SFZ `loop_mode` opcode
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum LoopMode{
+    NoLoop, // Plays to the end or until released
+    OneShot, // Plays to the end, ignoring note offs
+    LoopContinuous, // Loops for as long as the voice is sounding, release included
+    LoopSustain, // Loops until the note is released
+}
+
+impl SfzRegion{
+    /// Creates a region that plays the sample across the whole keyboard
+    pub fn new(sample: PooledSample)->Self{
+        SfzRegion{
+            lokey: u8::MIN,
+            hikey: u8::MAX,
+            lovel: f32::MIN,
+            hivel: f32::MAX,
+            pitch_keycenter: None,
+            offset: None,
+            end: None,
+            loop_mode: None,
+            loop_start: None,
+            loop_end: None,
+            group: 0,
+            seq_length: 1,
+            seq_position: 1,
+            lorand: 0.0,
+            hirand: 1.0,
+            sw_lokey: None,
+            sw_hikey: None,
+            sw_last: None,
+            sw_down: None,
+            sw_up: None,
+            sw_default: None,
+            sw_label: None,
+            trigger: Trigger::Attack,
+            rt_decay: 0.0,
+            cc_conditions: Vec::new(),
+            cc_triggers: Vec::new(),
+            choke_group: 0,
+            off_by: 0,
+            off_mode: OffMode::Fast,
+            volume: 0.0,
+            amplitude: 100.0,
+            pan: 0.0,
+            width: 100.0,
+            position: 0.0,
+            tune: 0.0,
+            transpose: 0,
+            pitch_keytrack: 100.0,
+            amp_veltrack: 100.0,
+            amp_velcurve: Vec::new(),
+            ampeg: AmpEnvelope::default(),
+            xfin_vel: (0.0, 0.0),
+            xfout_vel: (127.0, 127.0),
+            xfin_key: (0.0, 0.0),
+            xfout_key: (127.0, 127.0),
+            xf_velcurve: CrossfadeCurve::Power,
+            xf_keycurve: CrossfadeCurve::Power,
+            sample,
+        }
+    }
+    /// Creates a region from its (already inherited) opcodes
+    fn from_opcodes(opcodes: &HashMap<String, String>, group: usize, sample: PooledSample)->Self{
+        let mut region = SfzRegion::new(sample);
+        region.group = group;
+        // `key` sets the range and the root in one go, the more specific opcodes win
+        if let Some(key) = opcodes.get("key").and_then(|value| parse_note(value)){
+            region.lokey = key;
+            region.hikey = key;
+            region.pitch_keycenter = Some(key);
+        }
+        if let Some(value) = opcodes.get("lokey").and_then(|value| parse_note(value)){
+            region.lokey = value;
+        }
+        if let Some(value) = opcodes.get("hikey").and_then(|value| parse_note(value)){
+            region.hikey = value;
+        }
+        if let Some(value) = opcodes.get("lovel").and_then(|value| value.parse::<u8>().ok()){
+            region.lovel = value as f32;
+        }
+        if let Some(value) = opcodes.get("hivel").and_then(|value| value.parse::<u8>().ok()){
+            region.hivel = value as f32;
+        }
+        if let Some(value) = opcodes.get("pitch_keycenter").and_then(|value| parse_note(value)){
+            region.pitch_keycenter = Some(value);
+        }
+        region.offset = opcodes.get("offset").and_then(|value| value.parse().ok());
+        region.end = opcodes.get("end").and_then(|value| value.parse().ok());
+        region.loop_start = opcodes.get("loop_start").or(opcodes.get("loopstart"))
+            .and_then(|value| value.parse().ok());
+        region.loop_end = opcodes.get("loop_end").or(opcodes.get("loopend"))
+            .and_then(|value| value.parse().ok());
+        region.loop_mode = match opcodes.get("loop_mode").or(opcodes.get("loopmode")).map(|value| value.as_str()){
+            Some("no_loop") => Some(LoopMode::NoLoop),
+            Some("one_shot") => Some(LoopMode::OneShot),
+            Some("loop_continuous") => Some(LoopMode::LoopContinuous),
+            Some("loop_sustain") => Some(LoopMode::LoopSustain),
+            _ => None,
+        };
+        if let Some(value) = opcodes.get("seq_length").and_then(|value| value.parse::<u32>().ok()){
+            region.seq_length = value.max(1);
+        }
+        if let Some(value) = opcodes.get("seq_position").and_then(|value| value.parse::<u32>().ok()){
+            region.seq_position = value.max(1);
+        }
+        if let Some(value) = opcodes.get("lorand").and_then(|value| value.parse::<f32>().ok()){
+            region.lorand = value;
+        }
+        if let Some(value) = opcodes.get("hirand").and_then(|value| value.parse::<f32>().ok()){
+            region.hirand = value;
+        }
+        region.sw_lokey = opcodes.get("sw_lokey").and_then(|value| parse_note(value));
+        region.sw_hikey = opcodes.get("sw_hikey").and_then(|value| parse_note(value));
+        region.sw_last = opcodes.get("sw_last").and_then(|value| parse_note(value));
+        region.sw_down = opcodes.get("sw_down").and_then(|value| parse_note(value));
+        region.sw_up = opcodes.get("sw_up").and_then(|value| parse_note(value));
+        region.sw_default = opcodes.get("sw_default").and_then(|value| parse_note(value));
+        region.sw_label = opcodes.get("sw_label").cloned();
+        region.trigger = match opcodes.get("trigger").map(|value| value.as_str()){
+            Some("release") => Trigger::Release,
+            Some("release_key") => Trigger::ReleaseKey,
+            _ => Trigger::Attack,
+        };
+        if let Some(value) = opcodes.get("rt_decay").and_then(|value| value.parse::<f32>().ok()){
+            region.rt_decay = value.max(0.0);
+        }
+        region.cc_conditions = parse_cc_ranges(opcodes, "locc", "hicc");
+        region.cc_triggers = parse_cc_ranges(opcodes, "on_locc", "on_hicc");
+        region.choke_group = opcodes.get("group").and_then(|value| value.parse().ok()).unwrap_or(0);
+        region.off_by = opcodes.get("off_by").and_then(|value| value.parse().ok()).unwrap_or(0);
+        if opcodes.get("off_mode").map(|value| value.as_str()) == Some("normal"){
+            region.off_mode = OffMode::Normal;
+        }
+        let float = |opcode: &str| opcodes.get(opcode).and_then(|value| value.parse::<f32>().ok());
+        region.volume = float("volume").unwrap_or(region.volume);
+        region.amplitude = float("amplitude").unwrap_or(region.amplitude);
+        region.pan = float("pan").unwrap_or(region.pan);
+        region.width = float("width").unwrap_or(region.width);
+        region.position = float("position").unwrap_or(region.position);
+        region.tune = float("tune").unwrap_or(region.tune);
+        region.transpose = opcodes.get("transpose").and_then(|value| value.parse().ok()).unwrap_or(0);
+        region.pitch_keytrack = float("pitch_keytrack").unwrap_or(region.pitch_keytrack);
+        region.amp_veltrack = float("amp_veltrack").unwrap_or(region.amp_veltrack);
+        region.amp_velcurve = opcodes.iter()
+            .filter_map(|(opcode, value)| {
+                let velocity = opcode.strip_prefix("amp_velcurve_")?.parse::<u8>().ok()?;
+                Some((velocity.min(127) as f32, value.parse::<f32>().ok()?))
+            })
+            .collect();
+        region.amp_velcurve.sort_by(|a, b| a.0.total_cmp(&b.0));
+        let note = |opcode: &str| opcodes.get(opcode).and_then(|value| parse_note(value)).map(|note| note as f32);
+        region.xfin_vel = (float("xfin_lovel").unwrap_or(0.0), float("xfin_hivel").unwrap_or(0.0));
+        region.xfout_vel = (float("xfout_lovel").unwrap_or(127.0), float("xfout_hivel").unwrap_or(127.0));
+        region.xfin_key = (note("xfin_lokey").unwrap_or(0.0), note("xfin_hikey").unwrap_or(0.0));
+        region.xfout_key = (note("xfout_lokey").unwrap_or(127.0), note("xfout_hikey").unwrap_or(127.0));
+        let curve = |opcode: &str| match opcodes.get(opcode).map(|value| value.as_str()){
+            Some("gain") => CrossfadeCurve::Gain,
+            _ => CrossfadeCurve::Power,
+        };
+        region.xf_velcurve = curve("xf_velcurve");
+        region.xf_keycurve = curve("xf_keycurve");
+        region.ampeg = AmpEnvelope{
+            delay: float("ampeg_delay"),
+            attack: float("ampeg_attack"),
+            hold: float("ampeg_hold"),
+            decay: float("ampeg_decay"),
+            sustain: float("ampeg_sustain"),
+            release: float("ampeg_release"),
+        };
+        region
+    }
+    /// Returns whether the region should sound for the given note and velocity (0-1)
+    pub fn matches(&self, note: u8, velocity: f32)->bool{
+        note >= self.lokey && note <= self.hikey
+            && velocity*127.0 >= self.lovel && velocity*127.0 <= self.hivel
+    }
+    /// Returns whether the region's round robin step comes up for the given hit count
+    pub fn in_sequence(&self, counter: u32)->bool{
+        counter % self.seq_length + 1 == self.seq_position
+    }
+    /// Returns the linear gain of the region for a note and velocity (0-1), combining
+    /// `volume`, `amplitude`, the velocity curve and the crossfades
+    pub fn gain(&self, note: u8, velocity: f32)->f32{
+        10.0_f32.powf(self.volume / 20.0) * self.amplitude * 0.01
+            * self.velocity_gain(velocity) * self.crossfade_gain(note, velocity)
+    }
+    /// Returns the gain from the region's velocity and key crossfades
+    pub fn crossfade_gain(&self, note: u8, velocity: f32)->f32{
+        let velocity = velocity * 127.0;
+        let note = note as f32;
+        self.xf_velcurve.fade_in(velocity, self.xfin_vel) * self.xf_velcurve.fade_out(velocity, self.xfout_vel)
+            * self.xf_keycurve.fade_in(note, self.xfin_key) * self.xf_keycurve.fade_out(note, self.xfout_key)
+    }
+    /// Returns the gain for a velocity (0-1), following `amp_veltrack`. Negative
+    /// tracking makes softer notes louder
+    pub fn velocity_gain(&self, velocity: f32)->f32{
+        let track = self.amp_veltrack.clamp(-100.0, 100.0) * 0.01;
+        let curve = if track < 0.0 {self.velocity_curve(1.0 - velocity)} else {self.velocity_curve(velocity)};
+        1.0 - track.abs() + track.abs() * curve
+    }
+    /// Looks up a velocity (0-1) on the `amp_velcurve_N` points, interpolating between
+    /// them. The curve starts at 0 and ends at 1 unless the points say otherwise, and
+    /// is velocity squared if there are no points at all
+    fn velocity_curve(&self, velocity: f32)->f32{
+        let velocity = velocity.clamp(0.0, 1.0);
+        if self.amp_velcurve.is_empty(){
+            return velocity * velocity
+        }
+        let velocity = velocity * 127.0;
+        let mut previous = (0.0, 0.0);
+        for point in self.amp_velcurve.iter().copied().chain(std::iter::once((127.0, 1.0))){
+            if velocity <= point.0{
+                let span = point.0 - previous.0;
+                if span <= 0.0{
+                    return point.1
+                }
+                return previous.1 + (point.1 - previous.1) * (velocity - previous.0) / span
+            }
+            previous = point;
+        }
+        previous.1
+    }
+    /// Returns the tuning of the region in semitones, from `transpose` and `tune`
+    pub fn tuning(&self)->f32{
+        self.transpose as f32 + self.tune * 0.01
+    }
+    /// Returns whether the region starts on note off rather than note on
+    pub fn is_release(&self)->bool{
+        self.trigger != Trigger::Attack
+    }
+    /// Returns the gain of a release triggered region after the note was held
+    /// for the given number of seconds
+    pub fn release_gain(&self, held_seconds: f32)->f32{
+        10.0_f32.powf(-self.rt_decay * held_seconds.max(0.0) / 20.0)
+    }
+    /// Returns whether the region is started by CCs rather than by notes
+    pub fn is_cc_triggered(&self)->bool{
+        !self.cc_triggers.is_empty()
+    }
+    /// Returns whether every CC the region depends on is in range. `ccs` holds the
+    /// values (0-1) of the channel's CCs
+    pub fn cc_conditions_met(&self, ccs: &[f32; 128])->bool{
+        self.cc_conditions.iter().all(|range| range.contains(ccs[range.cc as usize]))
+    }
+    /// Returns whether a note's random value (0-1) falls in the region's random range
+    pub fn in_random_range(&self, random: f32)->bool{
+        random >= self.lorand && random < self.hirand
+    }
+}
+
+/// An SFZ instrument whose samples have all been decoded into a shared pool
+#[derive(Clone)]
+pub struct SfzInstrument{
+    pub regions: Vec<SfzRegion>,
+    pub pool: SamplePool,
+    /// The name the file gives the instrument, such as a SoundFont preset's
+    pub name: Option<String>,
+    /// How many times each group has been hit, for round robins
+    sequence_counters: Vec<u32>,
+    // Scratch space marking which groups were hit by the current note
+    group_hits: Vec<bool>,
+    /// Which of the 128 keys are keyswitches rather than notes
+    keyswitches: Vec<bool>,
+    /// Which of the 128 keys are currently held down
+    keys_down: Vec<bool>,
+    /// The frame each key was last pressed on and its velocity, for release triggers
+    key_presses: Vec<(u64, f32)>,
+    /// The last keyswitch pressed, starting from `sw_default`
+    last_keyswitch: Option<u8>,
+}
+
+impl Default for SfzInstrument{
+    fn default()->Self{
+        SfzInstrument::new(Vec::new(), SamplePool::new())
+    }
+}
+
+impl SfzInstrument{
+    /// Creates an instrument from its regions and the pool holding their samples
+    pub fn new(regions: Vec<SfzRegion>, pool: SamplePool)->Self{
+        let num_groups = regions.iter().map(|region| region.group + 1).max().unwrap_or(0);
+        let mut keyswitches = vec![false; 128];
+        for region in regions.iter(){
+            if let (Some(lokey), Some(hikey)) = (region.sw_lokey, region.sw_hikey){
+                for key in lokey..=hikey.min(127){
+                    keyswitches[key as usize] = true;
+                }
+            }
+            for key in [region.sw_last, region.sw_down, region.sw_up].into_iter().flatten(){
+                keyswitches[key as usize] = true;
+            }
+        }
+        let last_keyswitch = regions.iter().find_map(|region| region.sw_default);
+        SfzInstrument{
+            regions,
+            pool,
+            name: None,
+            sequence_counters: vec![0; num_groups],
+            group_hits: vec![false; num_groups],
+            keyswitches,
+            keys_down: vec![false; 128],
+            key_presses: vec![(0, 0.0); 128],
+            last_keyswitch,
+        }
+    }
+    /// Records a key being pressed on the given frame. Returns true if the key is a
+    /// keyswitch, in which case it should not sound
+    pub fn key_down(&mut self, note: u8, velocity: f32, frame: u64)->bool{
+        let key = note.min(127) as usize;
+        self.keys_down[key] = true;
+        self.key_presses[key] = (frame, velocity);
+        if self.keyswitches[key]{
+            self.last_keyswitch = Some(note);
+        }
+        self.keyswitches[key]
+    }
+    /// Records a key being released. Returns the frame the key was pressed on and
+    /// its velocity, or None if it wasn't held or is a keyswitch
+    pub fn key_up(&mut self, note: u8)->Option<(u64, f32)>{
+        let key = note.min(127) as usize;
+        let was_down = std::mem::replace(&mut self.keys_down[key], false);
+        if !was_down || self.keyswitches[key]{
+            return None
+        }
+        Some(self.key_presses[key])
+    }
+    /// Returns the last keyswitch pressed, or the default one if none has been
+    pub fn active_keyswitch(&self)->Option<u8>{
+        self.last_keyswitch
+    }
+    /// Returns the keyswitch notes selecting an articulation along with their names,
+    /// sorted by note. Keyswitches without an `sw_label` are named after their note
+    pub fn articulations(&self)->Vec<(u8, String)>{
+        let mut articulations: Vec<(u8, String)> = Vec::new();
+        for region in self.regions.iter(){
+            let Some(key) = region.sw_last else { continue };
+            if articulations.iter().any(|(existing, _)| *existing == key){
+                continue;
+            }
+            let name = region.sw_label.clone().unwrap_or_else(|| note_name(key));
+            articulations.push((key, name));
+        }
+        articulations.sort_by_key(|(key, _)| *key);
+        articulations
+    }
+    /// Returns whether the region at the given index should start for a note on, or
+    /// for a note off if `release` is set. 
+    /// 
+    /// `random` is the note's random value (0-1), shared by every region it could trigger,
+    /// and `ccs` holds the CC values (0-1) of the note's channel
+    pub fn is_triggered(&self, region_idx: usize, release: bool, note: u8, velocity: f32, random: f32, ccs: &[f32; 128])->bool{
+        let region = &self.regions[region_idx];
+        !region.is_cc_triggered()
+            && region.is_release() == release
+            && region.matches(note, velocity)
+            && region.in_sequence(self.sequence_counters[region.group])
+            && self.conditions_met(region, random, ccs)
+    }
+    /// Returns whether the region at the given index should start because a CC moved
+    /// from `previous` to `value` (both 0-1), entering one of its trigger ranges
+    pub fn is_cc_triggered(&self, region_idx: usize, cc: u8, previous: f32, value: f32, random: f32, ccs: &[f32; 128])->bool{
+        let region = &self.regions[region_idx];
+        region.cc_triggers.iter()
+            .any(|range| range.cc == cc && !range.contains(previous) && range.contains(value))
+            && self.conditions_met(region, random, ccs)
+    }
+    /// Checks the conditions shared by note and CC triggers: the random range,
+    /// keyswitches and CC ranges
+    fn conditions_met(&self, region: &SfzRegion, random: f32, ccs: &[f32; 128])->bool{
+        region.in_random_range(random)
+            && region.cc_conditions_met(ccs)
+            && region.sw_last.is_none_or(|key| self.last_keyswitch == Some(key))
+            && region.sw_down.is_none_or(|key| self.keys_down[key as usize])
+            && region.sw_up.is_none_or(|key| !self.keys_down[key as usize])
+    }
+    /// Advances the round robin of every group with a region matching the note on,
+    /// or note off if `release` is set.
+    /// 
+    /// Call this after the note's regions have been triggered
+    pub fn advance_sequences(&mut self, release: bool, note: u8, velocity: f32){
+        for region in self.regions.iter(){
+            if region.is_release() == release && region.matches(note, velocity){
+                self.group_hits[region.group] = true;
+            }
+        }
+        for (counter, hit) in self.sequence_counters.iter_mut().zip(self.group_hits.iter_mut()){
+            if *hit{
+                *counter = counter.wrapping_add(1);
+                *hit = false;
+            }
+        }
+    }
+    /// Forgets which keys are held, the round robin positions and the last keyswitch,
+    /// as if the instrument had just been loaded. Doesn't allocate
+    pub fn reset_state(&mut self){
+        self.sequence_counters.fill(0);
+        self.group_hits.fill(false);
+        self.keys_down.fill(false);
+        self.key_presses.fill((0, 0.0));
+        self.last_keyswitch = self.regions.iter().find_map(|region| region.sw_default);
+    }
+    /// Parses an SFZ file and decodes every sample it references. Sample paths are
+    /// resolved relative to the folder the SFZ file is in.
+    ///
+    /// Anything that had to be skipped is returned as warnings alongside the instrument.
+    /// Reads from disk, so this must never be called on the audio thread
+    pub fn from_file(path: &Path)->Result<(Self, Vec<SfzWarning>), SfzError>{
+        let text = fs::read_to_string(path)
+            .map_err(|error| SfzError::Unreadable{ path: path.to_path_buf(), error })?;
+        let sfz_dir = path.parent().unwrap_or(Path::new(""));
+        let (parsed_regions, mut warnings) = parse_sfz(&text);
+        let mut pool = SamplePool::new();
+        let mut regions = Vec::new();
+        for parsed in parsed_regions.iter(){
+            // Regions without a playable sample can never sound
+            let Some(sample_path) = parsed.opcodes.get("sample") else {
+                warnings.push(SfzWarning::NoSample{ line: parsed.line });
+                continue
+            };
+            let sample_path = resolve_sample_path(sfz_dir, sample_path);
+            let sample = match pool.get_or_load(&sample_path){
+                Ok(sample) => sample,
+                Err(LoadError::Io{ .. }) => {
+                    warnings.push(SfzWarning::MissingSample{ line: parsed.line, path: sample_path });
+                    continue
+                },
+                Err(error) => {
+                    warnings.push(SfzWarning::UndecodableSample{ line: parsed.line, reason: error.to_string() });
+                    continue
+                },
+            };
+            regions.push(SfzRegion::from_opcodes(&parsed.opcodes, parsed.group, sample));
+        }
+        if regions.is_empty(){
+            return Err(SfzError::NoRegions{ warnings })
+        }
+        Ok((SfzInstrument::new(regions, pool), warnings))
+    }
+}
+
+/// Why an SFZ file couldn't be loaded at all
+#[derive(Debug)]
+pub enum SfzError{
+    Unreadable{ path: PathBuf, error: io::Error },
+    /// None of the regions could be played. The warnings say why
+    NoRegions{ warnings: Vec<SfzWarning> },
+}
+
+impl fmt::Display for SfzError{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>)->fmt::Result{
+        match self{
+            SfzError::Unreadable{ path, error } => write!(f, "Couldn't read {}: {}", path.display(), error),
+            SfzError::NoRegions{ warnings } => write!(f, "The file has no playable regions ({} warnings)", warnings.len()),
+        }
+    }
+}
+
+impl SfzError{
+    /// Returns the warnings that explain why nothing could be played, if there are any
+    pub fn warnings(&self)->&[SfzWarning]{
+        match self{
+            SfzError::NoRegions{ warnings } => warnings,
+            _ => &[],
+        }
+    }
+}
+
+impl std::error::Error for SfzError{}
+
+/// Something in an SFZ file that was skipped while loading it
+#[derive(Clone, Debug, PartialEq)]
+pub enum SfzWarning{
+    /// The region's sample doesn't exist or couldn't be read
+    MissingSample{ line: usize, path: PathBuf },
+    /// The region's sample is in an unsupported format or is damaged
+    UndecodableSample{ line: usize, reason: String },
+    /// The region has no `sample` opcode
+    NoSample{ line: usize },
+    UnsupportedOpcode{ line: usize, opcode: String },
+    /// An opcode written before any header, which applies to nothing
+    IgnoredOpcode{ line: usize, opcode: String },
+    OutOfRange{ line: usize, opcode: String, value: String },
+    /// Headers such as `<curve>` or `<effect>`, whose opcodes are all ignored
+    UnsupportedHeader{ line: usize, header: String },
+    /// Preprocessor lines such as `#define` and `#include`
+    UnsupportedDirective{ line: usize, directive: String },
+}
+
+impl SfzWarning{
+    /// Returns the line of the SFZ file the warning is about, counting from 1
+    pub fn line(&self)->usize{
+        match self{
+            SfzWarning::MissingSample{ line, .. }
+            | SfzWarning::UndecodableSample{ line, .. }
+            | SfzWarning::NoSample{ line }
+            | SfzWarning::UnsupportedOpcode{ line, .. }
+            | SfzWarning::IgnoredOpcode{ line, .. }
+            | SfzWarning::OutOfRange{ line, .. }
+            | SfzWarning::UnsupportedHeader{ line, .. }
+            | SfzWarning::UnsupportedDirective{ line, .. } => *line,
+        }
+    }
+}
+
+impl fmt::Display for SfzWarning{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>)->fmt::Result{
+        write!(f, "Line {}: ", self.line())?;
+        match self{
+            SfzWarning::MissingSample{ path, .. } => write!(f, "sample {} couldn't be loaded", path.display()),
+            SfzWarning::UndecodableSample{ reason, .. } => write!(f, "{}", reason),
+            SfzWarning::NoSample{ .. } => write!(f, "region has no sample"),
+            SfzWarning::UnsupportedOpcode{ opcode, .. } => write!(f, "{} isn't supported", opcode),
+            SfzWarning::IgnoredOpcode{ opcode, .. } => write!(f, "{} isn't under a header", opcode),
+            SfzWarning::OutOfRange{ opcode, value, .. } => write!(f, "{}={} is out of range", opcode, value),
+            SfzWarning::UnsupportedHeader{ header, .. } => write!(f, "<{}> headers aren't supported", header),
+            SfzWarning::UnsupportedDirective{ directive, .. } => write!(f, "{} isn't supported", directive),
+        }
+    }
+}
+
+/// The values an opcode accepts
+#[derive(Clone, Copy)]
+enum OpcodeValue{
+    Text,
+    Note,
+    Int(i64, i64),
+    Float(f32, f32),
+    Choice(&'static [&'static str]),
+}
+
+impl OpcodeValue{
+    /// Returns whether the value is one the opcode accepts
+    fn accepts(&self, value: &str)->bool{
+        match *self{
+            OpcodeValue::Text => true,
+            OpcodeValue::Note => parse_note(value).is_some(),
+            OpcodeValue::Int(min, max) => value.parse::<i64>().is_ok_and(|value| value >= min && value <= max),
+            OpcodeValue::Float(min, max) => value.parse::<f32>().is_ok_and(|value| value >= min && value <= max),
+            OpcodeValue::Choice(choices) => choices.contains(&value),
+        }
+    }
+    /// Returns a number moved into the opcode's range, or None if the value isn't a
+    /// number the range applies to
+    fn clamp(&self, value: &str)->Option<String>{
+        match *self{
+            OpcodeValue::Int(min, max) => value.parse::<i64>().ok().map(|value| value.clamp(min, max).to_string()),
+            OpcodeValue::Float(min, max) => value.parse::<f32>().ok().map(|value| value.clamp(min, max).to_string()),
+            _ => None,
+        }
+    }
+}
+
+/// Every region opcode the sampler understands, with the values it accepts
+const OPCODES: &[(&str, OpcodeValue)] = &[
+    ("sample", OpcodeValue::Text),
+    ("key", OpcodeValue::Note),
+    ("lokey", OpcodeValue::Note),
+    ("hikey", OpcodeValue::Note),
+    ("lovel", OpcodeValue::Int(0, 127)),
+    ("hivel", OpcodeValue::Int(0, 127)),
+    ("pitch_keycenter", OpcodeValue::Note),
+    ("offset", OpcodeValue::Int(0, u32::MAX as i64)),
+    ("end", OpcodeValue::Int(0, u32::MAX as i64)),
+    ("loop_start", OpcodeValue::Int(0, u32::MAX as i64)),
+    ("loopstart", OpcodeValue::Int(0, u32::MAX as i64)),
+    ("loop_end", OpcodeValue::Int(0, u32::MAX as i64)),
+    ("loopend", OpcodeValue::Int(0, u32::MAX as i64)),
+    ("loop_mode", OpcodeValue::Choice(&["no_loop", "one_shot", "loop_continuous", "loop_sustain"])),
+    ("loopmode", OpcodeValue::Choice(&["no_loop", "one_shot", "loop_continuous", "loop_sustain"])),
+    ("seq_length", OpcodeValue::Int(1, 100)),
+    ("seq_position", OpcodeValue::Int(1, 100)),
+    ("lorand", OpcodeValue::Float(0.0, 1.0)),
+    ("hirand", OpcodeValue::Float(0.0, 1.0)),
+    ("sw_lokey", OpcodeValue::Note),
+    ("sw_hikey", OpcodeValue::Note),
+    ("sw_last", OpcodeValue::Note),
+    ("sw_down", OpcodeValue::Note),
+    ("sw_up", OpcodeValue::Note),
+    ("sw_default", OpcodeValue::Note),
+    ("sw_label", OpcodeValue::Text),
+    ("trigger", OpcodeValue::Choice(&["attack", "release", "first", "legato", "release_key"])),
+    ("rt_decay", OpcodeValue::Float(0.0, 200.0)),
+    ("group", OpcodeValue::Int(i32::MIN as i64, i32::MAX as i64)),
+    ("off_by", OpcodeValue::Int(i32::MIN as i64, i32::MAX as i64)),
+    ("off_mode", OpcodeValue::Choice(&["fast", "normal"])),
+    ("volume", OpcodeValue::Float(-144.0, 6.0)),
+    ("amplitude", OpcodeValue::Float(0.0, 100.0)),
+    ("pan", OpcodeValue::Float(-100.0, 100.0)),
+    ("width", OpcodeValue::Float(-100.0, 100.0)),
+    ("position", OpcodeValue::Float(-100.0, 100.0)),
+    ("tune", OpcodeValue::Float(-100.0, 100.0)),
+    ("transpose", OpcodeValue::Int(-127, 127)),
+    ("pitch_keytrack", OpcodeValue::Float(-1200.0, 1200.0)),
+    ("amp_veltrack", OpcodeValue::Float(-100.0, 100.0)),
+    ("ampeg_delay", OpcodeValue::Float(0.0, 100.0)),
+    ("ampeg_attack", OpcodeValue::Float(0.0, 100.0)),
+    ("ampeg_hold", OpcodeValue::Float(0.0, 100.0)),
+    ("ampeg_decay", OpcodeValue::Float(0.0, 100.0)),
+    ("ampeg_sustain", OpcodeValue::Float(0.0, 100.0)),
+    ("ampeg_release", OpcodeValue::Float(0.0, 100.0)),
+    ("xfin_lovel", OpcodeValue::Int(0, 127)),
+    ("xfin_hivel", OpcodeValue::Int(0, 127)),
+    ("xfout_lovel", OpcodeValue::Int(0, 127)),
+    ("xfout_hivel", OpcodeValue::Int(0, 127)),
+    ("xfin_lokey", OpcodeValue::Note),
+    ("xfin_hikey", OpcodeValue::Note),
+    ("xfout_lokey", OpcodeValue::Note),
+    ("xfout_hikey", OpcodeValue::Note),
+    ("xf_velcurve", OpcodeValue::Choice(&["gain", "power"])),
+    ("xf_keycurve", OpcodeValue::Choice(&["gain", "power"])),
+];
+
+/// Opcodes ending in a number from 0 to 127, such as `locc64`, with the values they accept
+const NUMBERED_OPCODES: &[(&str, OpcodeValue)] = &[
+    ("locc", OpcodeValue::Int(0, 127)),
+    ("hicc", OpcodeValue::Int(0, 127)),
+    ("on_locc", OpcodeValue::Int(0, 127)),
+    ("on_hicc", OpcodeValue::Int(0, 127)),
+    ("amp_velcurve_", OpcodeValue::Float(0.0, 1.0)),
+];
+
+/// Checks a region opcode read on the given line, warning if it isn't supported or its
+/// value is out of range. Returns the value to use: numbers out of range are clamped
+/// into it, and any other value that isn't accepted is dropped
+fn check_opcode(name: &str, value: &str, line: usize, warnings: &mut Vec<SfzWarning>)->Option<String>{
+    let kind = OPCODES.iter()
+        .find(|(opcode, _)| *opcode == name)
+        .map(|(_, kind)| *kind)
+        .or_else(|| NUMBERED_OPCODES.iter().find_map(|(prefix, kind)| {
+            let number = name.strip_prefix(prefix)?.parse::<u8>().ok()?;
+            (number <= 127).then_some(*kind)
+        }));
+    let Some(kind) = kind else {
+        warnings.push(SfzWarning::UnsupportedOpcode{ line, opcode: name.to_string() });
+        return Some(value.to_string())
+    };
+    if kind.accepts(value){
+        return Some(value.to_string())
+    }
+    warnings.push(SfzWarning::OutOfRange{ line, opcode: name.to_string(), value: value.to_string() });
+    kind.clamp(value)
+}
+
+/// A region as written in the SFZ file, with the opcodes of its headers merged in
+#[derive(Clone, Debug, Default)]
+pub struct ParsedRegion{
+    pub opcodes: HashMap<String, String>,
+    /// Line of the `<region>` header, counting from 1
+    pub line: usize,
+    /// Index of the group the region is in. Regions share a group until the next
+    /// `<group>`, `<master>` or `<global>` header
+    pub group: usize,
+}
+
+/// The header whose opcodes are currently being read
+#[derive(Clone, Copy, PartialEq)]
+enum Header{
+    None,
+    Control,
+    Global,
+    Master,
+    Group,
+    Region,
+    Other, // Headers such as <curve> or <effect>, whose opcodes don't apply to regions
+}
+
+/// Parses the text of an SFZ file into its regions, along with warnings about anything
+/// that isn't supported.
+///
+/// Opcodes set in `<global>`, `<master>` and `<group>` headers are inherited by the regions
+/// that follow them, with more specific headers taking priority. The `<control>` header's
+/// `default_path` is prepended to every sample path after it.
+pub fn parse_sfz(text: &str)->(Vec<ParsedRegion>, Vec<SfzWarning>){
+    let mut regions: Vec<ParsedRegion> = Vec::new();
+    let mut warnings = Vec::new();
+    let mut global = HashMap::new();
+    let mut master = HashMap::new();
+    let mut group = HashMap::new();
+    let mut default_path = String::new();
+    let mut group_idx = 0;
+    let mut header = Header::None;
+    let text = strip_block_comments(text);
+    for (line_idx, line) in text.lines().enumerate(){
+        let line = match line.find("//"){
+            Some(comment) => &line[..comment],
+            None => line,
+        };
+        // Preprocessor lines such as #define and #include aren't supported
+        if line.trim_start().starts_with('#'){
+            let directive = line.split_whitespace().next().unwrap_or("#").to_string();
+            warnings.push(SfzWarning::UnsupportedDirective{ line: line_idx + 1, directive });
+            continue;
+        }
+        for token in tokenize_line(line){
+            match token{
+                Token::Header(name) => {
+                    header = match name{
+                        "control" => Header::Control,
+                        "global" => {
+                            group_idx += 1;
+                            global.clear();
+                            master.clear();
+                            group.clear();
+                            Header::Global
+                        },
+                        "master" => {
+                            group_idx += 1;
+                            master.clear();
+                            group.clear();
+                            Header::Master
+                        },
+                        "group" => {
+                            group_idx += 1;
+                            group.clear();
+                            Header::Group
+                        },
+                        "region" => {
+                            let mut opcodes = global.clone();
+                            opcodes.extend(master.clone());
+                            opcodes.extend(group.clone());
+                            regions.push(ParsedRegion{ opcodes, line: line_idx + 1, group: group_idx });
+                            Header::Region
+                        },
+                        _ => {
+                            warnings.push(SfzWarning::UnsupportedHeader{ line: line_idx + 1, header: name.to_string() });
+                            Header::Other
+                        },
+                    };
+                },
+                Token::Opcode(name, value) => {
+                    let checked = match header{
+                        Header::Global | Header::Master | Header::Group | Header::Region => {
+                            check_opcode(name, value, line_idx + 1, &mut warnings)
+                        },
+                        Header::Control if name != "default_path" => {
+                            warnings.push(SfzWarning::UnsupportedOpcode{ line: line_idx + 1, opcode: name.to_string() });
+                            Some(value.to_string())
+                        },
+                        Header::None => {
+                            warnings.push(SfzWarning::IgnoredOpcode{ line: line_idx + 1, opcode: name.to_string() });
+                            Some(value.to_string())
+                        },
+                        Header::Control | Header::Other => Some(value.to_string()),
+                    };
+                    let Some(mut value) = checked else {
+                        continue
+                    };
+                    if name == "sample"{
+                        value = format!("{}{}", default_path, value);
+                    }
+                    match header{
+                        Header::Control => {
+                            if name == "default_path"{
+                                default_path = value;
+                            }
+                        },
+                        Header::Global => {global.insert(name.to_string(), value);},
+                        Header::Master => {master.insert(name.to_string(), value);},
+                        Header::Group => {group.insert(name.to_string(), value);},
+                        Header::Region => {
+                            if let Some(region) = regions.last_mut(){
+                                region.opcodes.insert(name.to_string(), value);
+                            }
+                        },
+                        Header::None | Header::Other => {},
+                    }
+                }
+            }
+        }
+    }
+    (regions, warnings)
+}
+
+/// Turns a sample path from an SFZ file into a path on this machine. Relative paths
+/// are relative to the SFZ file's folder, and Windows separators are accepted everywhere
+pub fn resolve_sample_path(sfz_dir: &Path, sample: &str)->PathBuf{
+    let sample = PathBuf::from(sample.replace('\\', "/"));
+    if sample.is_absolute(){
+        sample
+    }else{
+        sfz_dir.join(sample)
+    }
+}
+
+/// Parses a MIDI note given either as a number or a note name such as `c#4` (c4 = 60)
+pub fn parse_note(value: &str)->Option<u8>{
+    if let Ok(note) = value.parse::<i32>(){
+        return u8::try_from(note).ok().filter(|note| *note <= 127)
+    }
+    let value = value.to_lowercase();
+    let mut chars = value.chars();
+    let mut note: i32 = match chars.next()?{
+        'c' => 0,
+        'd' => 2,
+        'e' => 4,
+        'f' => 5,
+        'g' => 7,
+        'a' => 9,
+        'b' => 11,
+        _ => return None,
+    };
+    let mut rest = chars.as_str();
+    if let Some(stripped) = rest.strip_prefix('#'){
+        note += 1;
+        rest = stripped;
+    }else if let Some(stripped) = rest.strip_prefix('b'){
+        note -= 1;
+        rest = stripped;
+    }
+    let octave: i32 = rest.parse().ok()?;
+    u8::try_from(note + (octave + 1) * 12).ok().filter(|note| *note <= 127)
+}
+
+/// Collects the CC ranges set by numbered opcode pairs such as `locc64`/`hicc64`.
+/// A range missing one of its ends covers 0 or 127 on that side
+fn parse_cc_ranges(opcodes: &HashMap<String, String>, lo_prefix: &str, hi_prefix: &str)->Vec<CcRange>{
+    let mut ranges: Vec<CcRange> = Vec::new();
+    for (opcode, value) in opcodes.iter(){
+        let (is_lo, number) = if let Some(number) = opcode.strip_prefix(lo_prefix){
+            (true, number)
+        }else if let Some(number) = opcode.strip_prefix(hi_prefix){
+            (false, number)
+        }else{
+            continue
+        };
+        let (Ok(cc), Ok(value)) = (number.parse::<u8>(), value.parse::<f32>()) else { continue };
+        if cc > 127{
+            continue
+        }
+        let idx = match ranges.iter().position(|range| range.cc == cc){
+            Some(idx) => idx,
+            None => {
+                ranges.push(CcRange{ cc, lo: 0.0, hi: 127.0 });
+                ranges.len() - 1
+            }
+        };
+        if is_lo{
+            ranges[idx].lo = value;
+        }else{
+            ranges[idx].hi = value;
+        }
+    }
+    ranges.sort_by_key(|range| range.cc);
+    ranges
+}
+
+/// Returns the name of a MIDI note, with c4 being note 60
+pub fn note_name(note: u8)->String{
+    const NAMES: [&str; 12] = ["c", "c#", "d", "d#", "e", "f", "f#", "g", "g#", "a", "a#", "b"];
+    format!("{}{}", NAMES[note as usize % 12], note as i32 / 12 - 1)
+}
+
+enum Token<'a>{
+    Header(&'a str),
+    Opcode(&'a str, &'a str),
+}
+
+/// Splits a line into headers and opcodes. Values run until the next opcode or header
+/// so sample paths may contain spaces
+fn tokenize_line(line: &str)->Vec<Token<'_>>{
+    let mut tokens = Vec::new();
+    let bytes = line.as_bytes();
+    let mut pos = 0;
+    while pos < bytes.len(){
+        if bytes[pos].is_ascii_whitespace(){
+            pos += 1;
+        }else if bytes[pos] == b'<'{
+            let Some(close) = line[pos..].find('>') else { break };
+            tokens.push(Token::Header(line[pos + 1..pos + close].trim()));
+            pos += close + 1;
+        }else{
+            let Some(equals) = line[pos..].find('=') else { break };
+            let name = line[pos..pos + equals].trim();
+            let value_start = pos + equals + 1;
+            let value_end = find_token_start(line, value_start);
+            tokens.push(Token::Opcode(name, line[value_start..value_end].trim()));
+            pos = value_end;
+        }
+    }
+    tokens
+}
+
+/// Returns where the next header or opcode after `from` starts, or the end of the line
+fn find_token_start(line: &str, from: usize)->usize{
+    let bytes = line.as_bytes();
+    let mut pos = from;
+    while pos < bytes.len(){
+        if bytes[pos] == b'<'{
+            return pos
+        }
+        if pos > from && bytes[pos - 1].is_ascii_whitespace() && !bytes[pos].is_ascii_whitespace(){
+            // A word directly followed by '=' is the next opcode
+            let word_end = line[pos..].find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+                .map_or(bytes.len(), |end| pos + end);
+            if word_end > pos && bytes.get(word_end) == Some(&b'='){
+                return pos
+            }
+        }
+        pos += 1;
+    }
+    bytes.len()
+}
+
+/// Removes /* */ comments while keeping line breaks, so line numbers stay correct
+fn strip_block_comments(text: &str)->String{
+    let mut out = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find("/*"){
+        out.push_str(&rest[..start]);
+        match rest[start..].find("*/"){
+            Some(end) => {
+                let comment = &rest[start..start + end + 2];
+                out.extend(comment.chars().filter(|c| *c == '\n'));
+                rest = &rest[start + end + 2..];
+            },
+            None => {
+                rest = "";
+            }
+        }
+    }
+    out.push_str(rest);
+    out
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+    use crate::sample_buffer::SampleBuffer;
+    #[test]
+    fn test_inheritance(){
+        let text = "
+            <control> default_path=Samples/Piano/
+            <global> ampeg_release=0.5 lovel=1
+            // comment <region> sample=ignored.wav
+            <group> lokey=40 hikey=50 /* lovel=99 */
+            <region> sample=C4 soft.wav pitch_keycenter=c4
+            <region> sample=D4.wav hikey=45 ampeg_release=1
+            <group> key=60
+            <region> sample=E4.wav
+        ";
+        let (regions, warnings) = parse_sfz(text);
+        assert!(warnings.is_empty());
+        assert_eq!(regions.len(), 3);
+        let first = &regions[0].opcodes;
+        assert_eq!(first["sample"], "Samples/Piano/C4 soft.wav");
+        assert_eq!(first["lokey"], "40");
+        assert_eq!(first["lovel"], "1");
+        assert_eq!(first["ampeg_release"], "0.5");
+        assert_eq!(parse_note(&first["pitch_keycenter"]), Some(60));
+        // Region opcodes override the group's and global's
+        let second = &regions[1].opcodes;
+        assert_eq!(second["hikey"], "45");
+        assert_eq!(second["ampeg_release"], "1");
+        // A new group drops the previous group's opcodes but keeps the global ones
+        let third = &regions[2].opcodes;
+        assert!(!third.contains_key("lokey"));
+        assert_eq!(third["key"], "60");
+        assert_eq!(third["lovel"], "1");
+        assert_eq!(regions[2].line, 9);
+        assert_eq!(regions[0].group, regions[1].group);
+        assert_ne!(regions[1].group, regions[2].group);
+    }
+    #[test]
+    fn test_relative_paths(){
+        let dir = std::env::temp_dir().join("rust_sampler_sfz_test");
+        fs::create_dir_all(dir.join("samples")).unwrap();
+        let spec = hound::WavSpec{
+            channels: 1,
+            sample_rate: 44100,
+            bits_per_sample: 16,
+            sample_format: hound::SampleFormat::Int,
+        };
+        let mut writer = hound::WavWriter::create(dir.join("samples").join("tone.wav"), spec).unwrap();
+        for i in 0..100{
+            writer.write_sample((i * 100) as i16).unwrap();
+        }
+        writer.finalize().unwrap();
+        fs::write(dir.join("test.sfz"), "<control> default_path=samples\\\n<region> sample=tone.wav key=60").unwrap();
+
+        let resolved = resolve_sample_path(&dir, "samples\\tone.wav");
+        assert_eq!(resolved, dir.join("samples").join("tone.wav"));
+        let (instrument, _) = SfzInstrument::from_file(&dir.join("test.sfz")).unwrap();
+        assert_eq!(instrument.regions.len(), 1);
+        assert_eq!(instrument.regions[0].sample.buffer.capacity(), 100);
+        assert_eq!(instrument.regions[0].pitch_keycenter, Some(60));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+    #[test]
+    fn test_crossfades(){
+        let text = "
+            <group> amp_veltrack=0
+            <region> sample=soft.wav xfout_lovel=40 xfout_hivel=80
+            <region> sample=loud.wav xfin_lovel=40 xfin_hivel=80 xf_velcurve=gain
+        ";
+        let sample = PooledSample{ buffer: std::sync::Arc::new(SampleBuffer::new(1, 1)), sample_rate: 44100.0, sustain_loop: None };
+        let regions: Vec<SfzRegion> = parse_sfz(text).0.iter()
+            .map(|parsed| SfzRegion::from_opcodes(&parsed.opcodes, parsed.group, sample.clone()))
+            .collect();
+        let (soft, loud) = (&regions[0], &regions[1]);
+        // Outside the crossfade only one layer sounds
+        assert_eq!(soft.gain(60, 30.0 / 127.0), 1.0);
+        assert_eq!(loud.gain(60, 30.0 / 127.0), 0.0);
+        assert_eq!(soft.gain(60, 1.0), 0.0);
+        assert_eq!(loud.gain(60, 1.0), 1.0);
+        // Halfway through, power curves sum to constant power and gain curves to constant gain
+        let half = 60.0 / 127.0;
+        assert!((soft.gain(60, half) - 0.5_f32.sqrt()).abs() < 1e-4);
+        assert!((loud.gain(60, half) - 0.5).abs() < 1e-4);
+    }
+    #[test]
+    fn test_load_report(){
+        let dir = std::env::temp_dir().join("rust_sampler_sfz_report_test");
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("test.sfz"), "\
+#define $KEY 60
+<control> set_cc1=64
+<group> pan=150 cutoff=400
+<region> sample=missing.wav
+<curve> v000=0
+").unwrap();
+        let Err(SfzError::NoRegions{ warnings }) = SfzInstrument::from_file(&dir.join("test.sfz")) else {
+            panic!("an instrument without samples shouldn't load")
+        };
+        assert_eq!(warnings, vec![
+            SfzWarning::UnsupportedDirective{ line: 1, directive: "#define".to_string() },
+            SfzWarning::UnsupportedOpcode{ line: 2, opcode: "set_cc1".to_string() },
+            SfzWarning::OutOfRange{ line: 3, opcode: "pan".to_string(), value: "150".to_string() },
+            SfzWarning::UnsupportedOpcode{ line: 3, opcode: "cutoff".to_string() },
+            SfzWarning::UnsupportedHeader{ line: 5, header: "curve".to_string() },
+            SfzWarning::MissingSample{ line: 4, path: dir.join("missing.wav") },
+        ]);
+        assert_eq!(warnings[2].to_string(), "Line 3: pan=150 is out of range");
+        // Out of range numbers are clamped into range, other bad values are dropped
+        let (regions, warnings) = parse_sfz("<group> pan=150 volume=40 <region> sample=a.wav lovel=-5 loop_mode=forever");
+        assert_eq!(warnings.len(), 4);
+        let opcodes = &regions[0].opcodes;
+        assert_eq!((opcodes["pan"].as_str(), opcodes["volume"].as_str(), opcodes["lovel"].as_str()), ("100", "6", "0"));
+        assert!(!opcodes.contains_key("loop_mode"));
+        assert!(matches!(SfzInstrument::from_file(&dir.join("nothing.sfz")), Err(SfzError::Unreadable{ .. })));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}